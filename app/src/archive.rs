@@ -0,0 +1,222 @@
+//! Settled games, moved out of the live games once `proveGames` confirmed them
+//!
+//! Archived games are appended to a file of JSON lines. Only the position of every record is kept
+//! in memory, by game id and by player, so the archive can grow without growing the dealer.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use alloy_primitives::{Bytes, B256, U256};
+use anyhow::{Context, Result};
+use k256::ecdsa::VerifyingKey;
+use serde::{Deserialize, Serialize};
+
+use crate::sm::Snapshot;
+use crate::store::open_log;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ArchivedGame {
+    /// State of the game as the dealer last saw it
+    pub snapshot: Snapshot,
+    /// `proveGames` transaction that settled the game, `None` if it was finished some other way,
+    /// e.g. reclaimed by the player
+    pub tx_hash: Option<B256>,
+}
+
+impl ArchivedGame {
+    pub fn game_id(&self) -> u64 {
+        self.snapshot.game_id
+    }
+
+    /// SEC1 encoded public key of the player
+    pub fn player_pubkey(&self) -> &Bytes {
+        &self.snapshot.player_pubkey
+    }
+
+    /// Total payout of the game as the dealer dealt it, zero if it wasn't terminated
+    pub fn winnings(&self) -> U256 {
+        self.snapshot.game.winnings.iter().sum()
+    }
+
+    pub fn terminated(&self) -> bool {
+        self.snapshot.game.hands_active.iter().all(|&active| !active)
+    }
+
+    /// Hand the next action would have been for, `None` if the game was terminated
+    pub fn current_hand(&self) -> Option<u8> {
+        self.snapshot.game.hands_active.iter().position(|&active| active).map(|hand| hand as u8)
+    }
+
+    /// Dealer's cards that can be shown to the player, as `BlackjackStateMachine` shows them
+    pub fn visible_dealer_hand(&self) -> &[u8] {
+        let dealer_hand = &self.snapshot.game.dealer_hand;
+        if self.terminated() {
+            dealer_hand
+        } else {
+            &dealer_hand[..1]
+        }
+    }
+}
+
+pub struct GameArchive {
+    path: PathBuf,
+    index: Mutex<Index>,
+}
+
+struct Index {
+    file: File,
+    /// Length of the file, i.e. where the next record starts
+    len: u64,
+    /// Offset of every game's record
+    games: HashMap<u64, u64>,
+    /// Ids of every player's games, in the order they were archived
+    players: HashMap<Bytes, Vec<u64>>,
+}
+
+impl GameArchive {
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let file = open_log(&path)?;
+        let mut index = Index {
+            file,
+            len: 0,
+            games: HashMap::new(),
+            players: HashMap::new(),
+        };
+        let mut reader = BufReader::new(File::open(&path)?);
+        let mut line = String::new();
+        for i in 1.. {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+            let game: ArchivedGame =
+                serde_json::from_str(&line).with_context(|| format!("{}:{}", path.display(), i))?;
+            index.add(&game, index.len);
+            index.len += read as u64;
+        }
+        Ok(Self {
+            path,
+            index: Mutex::new(index),
+        })
+    }
+
+    /// Appends a game, unless it is archived already
+    pub fn insert(&self, game: &ArchivedGame) -> Result<()> {
+        let mut line = serde_json::to_vec(game)?;
+        line.push(b'\n');
+        let mut index = self.index.lock().unwrap();
+        if index.games.contains_key(&game.game_id()) {
+            return Ok(());
+        }
+        index.file.write_all(&line)?;
+        index.file.sync_data()?;
+        let offset = index.len;
+        index.add(game, offset);
+        index.len += line.len() as u64;
+        Ok(())
+    }
+
+    pub fn get(&self, game_id: u64) -> Result<Option<ArchivedGame>> {
+        let offset = self.index.lock().unwrap().games.get(&game_id).copied();
+        offset.map(|offset| self.read(offset)).transpose()
+    }
+
+    /// Games of a player, in the order they were archived
+    pub fn by_player(&self, pubkey: &VerifyingKey) -> Result<Vec<ArchivedGame>> {
+        let offsets = {
+            let index = self.index.lock().unwrap();
+            index
+                .players
+                .get(&player_key(pubkey))
+                .into_iter()
+                .flatten()
+                .map(|game_id| index.games[game_id])
+                .collect::<Vec<_>>()
+        };
+        offsets.into_iter().map(|offset| self.read(offset)).collect()
+    }
+
+    fn read(&self, offset: u64) -> Result<ArchivedGame> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        serde_json::from_str(&line)
+            .with_context(|| format!("{} at {}", self.path.display(), offset))
+    }
+}
+
+impl Index {
+    fn add(&mut self, game: &ArchivedGame, offset: u64) {
+        self.games.insert(game.game_id(), offset);
+        // keys are indexed uncompressed, whatever encoding the snapshot holds
+        let player = VerifyingKey::from_sec1_bytes(game.player_pubkey())
+            .map_or_else(|_| game.player_pubkey().clone(), |pubkey| player_key(&pubkey));
+        self.players.entry(player).or_default().push(game.game_id());
+    }
+}
+
+fn player_key(pubkey: &VerifyingKey) -> Bytes {
+    pubkey.to_encoded_point(false).as_bytes().to_vec().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sm::BlackjackStateMachine;
+    use alloy_primitives::Address;
+    use k256::ecdsa::SigningKey;
+
+    fn game(game_id: u64, player: &SigningKey) -> ArchivedGame {
+        let sm = BlackjackStateMachine::new(
+            [1; 16],
+            [2; 16],
+            *player.verifying_key(),
+            vec![U256::from(1)],
+            1,
+            Address::ZERO,
+            game_id,
+        );
+        ArchivedGame {
+            snapshot: sm.snapshot(),
+            tx_hash: Some(B256::repeat_byte(game_id as u8)),
+        }
+    }
+
+    #[test]
+    fn test_archive_is_queryable_after_reopening() {
+        let path = std::env::temp_dir().join(format!("archive-{}.jsonl", std::process::id()));
+        let alice = SigningKey::from_slice(&[7; 32]).unwrap();
+        let bob = SigningKey::from_slice(&[8; 32]).unwrap();
+        let archive = GameArchive::open(&path).unwrap();
+        archive.insert(&game(1, &alice)).unwrap();
+        archive.insert(&game(2, &bob)).unwrap();
+        archive.insert(&game(3, &alice)).unwrap();
+        // archiving twice keeps the first record
+        archive
+            .insert(&ArchivedGame {
+                tx_hash: None,
+                ..game(1, &alice)
+            })
+            .unwrap();
+
+        let archive = GameArchive::open(&path).unwrap();
+        assert_eq!(archive.get(2).unwrap(), Some(game(2, &bob)));
+        assert_eq!(archive.get(4).unwrap(), None);
+        assert_eq!(
+            archive.by_player(alice.verifying_key()).unwrap(),
+            vec![game(1, &alice), game(3, &alice)]
+        );
+        archive.insert(&game(4, &bob)).unwrap();
+        assert_eq!(
+            archive.by_player(bob.verifying_key()).unwrap(),
+            vec![game(2, &bob), game(4, &bob)]
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,45 +1,490 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use alloy_primitives::U256;
-use app::eth;
-use app::web::{self, web_task};
-use clap::Parser;
+use anyhow::{Context, Result};
+use app::archive::GameArchive;
+use app::eth::{self, Blockchain};
+use app::payout::{self, PayoutConfig, Prepaid};
+use app::registry::GameRegistry;
+use app::seeds::{self, SeedManager};
+use app::settle::{self, SettleConfig};
+use app::store::{self, BlockCursor, FileStore};
+use app::web::{self, AppState};
+use clap::{Parser, Subcommand};
+use serde::Deserialize;
 
 /// Arguments of the publisher CLI.
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
-struct Args {
+struct Cli {
+    /// TOML file with any of the options, flags and environment variables take precedence
+    #[clap(long, env = "DEALER_CONFIG", global = true)]
+    config: Option<PathBuf>,
+
+    #[clap(flatten)]
+    options: Options,
+
+    #[clap(subcommand)]
+    command: Command,
+}
+
+/// Options every command needs to reach the contract
+#[derive(clap::Args, Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct Options {
     /// Ethereum chain ID
-    #[clap(long)]
-    chain_id: u64,
+    #[clap(long, env = "CHAIN_ID", global = true)]
+    chain_id: Option<u64>,
 
-    /// Ethereum Node endpoint.
-    #[clap(long, env)]
-    eth_wallet_private_key: String,
+    /// Private key of the dealer's account
+    #[clap(long, env = "ETH_WALLET_PRIVATE_KEY", global = true, hide_env_values = true)]
+    eth_wallet_private_key: Option<String>,
 
-    /// Ethereum Node endpoint.
-    #[clap(long)]
-    rpc_url: String,
+    /// Ethereum node websocket endpoint
+    #[clap(long, env = "RPC_URL", global = true)]
+    rpc_url: Option<String>,
 
     /// Application's contract address on Ethereum
-    #[clap(long)]
-    contract: String,
+    #[clap(long, env = "CONTRACT", global = true)]
+    contract: Option<String>,
+
+    /// Directory the dealer's seeds are stored in [default: seeds]
+    #[clap(long, env = "SEED_DIR", global = true)]
+    seed_dir: Option<PathBuf>,
+
+    /// Password the stored seeds are encrypted with
+    #[clap(long, env = "SEED_PASSWORD", global = true, hide_env_values = true)]
+    seed_password: Option<String>,
+
+    /// File the games being played are recorded in [default: games.jsonl]
+    #[clap(long, env = "GAME_STORE", global = true)]
+    store: Option<PathBuf>,
+
+    /// File settled games are archived in [default: archive.jsonl]
+    #[clap(long, env = "GAME_ARCHIVE", global = true)]
+    archive: Option<PathBuf>,
+
+    /// File the block the contract's events are followed from is saved in [default: cursor]
+    #[clap(long, env = "EVENT_CURSOR", global = true)]
+    cursor: Option<PathBuf>,
+
+    /// Options of `serve`, only read from the config file
+    #[clap(skip)]
+    serve: Option<ServeOptions>,
+}
+
+impl Options {
+    /// Fills in whatever wasn't given on the command line or in the environment from `file`
+    fn or(self, file: Options) -> Options {
+        Options {
+            chain_id: self.chain_id.or(file.chain_id),
+            eth_wallet_private_key: self.eth_wallet_private_key.or(file.eth_wallet_private_key),
+            rpc_url: self.rpc_url.or(file.rpc_url),
+            contract: self.contract.or(file.contract),
+            seed_dir: self.seed_dir.or(file.seed_dir),
+            seed_password: self.seed_password.or(file.seed_password),
+            store: self.store.or(file.store),
+            archive: self.archive.or(file.archive),
+            cursor: self.cursor.or(file.cursor),
+            serve: file.serve,
+        }
+    }
+
+    async fn connect(&self) -> Result<Blockchain> {
+        Blockchain::new(
+            self.chain_id.context("--chain-id is required")?,
+            self.rpc_url.as_deref().context("--rpc-url is required")?,
+            self.eth_wallet_private_key
+                .as_deref()
+                .context("--eth-wallet-private-key is required")?,
+            self.contract.as_deref().context("--contract is required")?,
+        )
+        .await
+    }
+
+    fn seeds(&self) -> Result<SeedManager> {
+        SeedManager::open(
+            self.seed_dir.as_deref().unwrap_or(Path::new("seeds")),
+            self.seed_password.as_deref().context("--seed-password is required")?,
+        )
+    }
+
+    fn store(&self) -> Result<FileStore> {
+        FileStore::open(self.store.as_deref().unwrap_or(Path::new("games.jsonl")))
+    }
+
+    fn archive(&self) -> Result<GameArchive> {
+        GameArchive::open(self.archive.as_deref().unwrap_or(Path::new("archive.jsonl")))
+    }
+
+    fn cursor(&self) -> BlockCursor {
+        BlockCursor::new(self.cursor.as_deref().unwrap_or(Path::new("cursor")))
+    }
+}
 
-    /// The input to provide to the guest binary
-    #[clap(short, long)]
-    input: U256,
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Deals games to players, pays them out and settles the games on chain
+    Serve(ServeOptions),
+    /// Registers the account as a dealer committed to a freshly generated seed. The account has
+    /// to be whitelisted by the register authority.
+    Register {
+        /// Smallest bet per hand, in ether
+        #[clap(long)]
+        min_bet: Ether,
+        /// Largest bet per hand, in ether
+        #[clap(long)]
+        max_bet: Ether,
+        /// Fee per game, in ether
+        #[clap(long, default_value = "0")]
+        fee: Ether,
+        /// Blocks after which the player can reclaim a game that wasn't settled
+        #[clap(long)]
+        timeout_blocks: u64,
+    },
+    /// Adds to the dealer's bankroll, only possible while offline
+    Deposit {
+        /// Amount in ether
+        amount: Ether,
+    },
+    /// Takes out of the dealer's bankroll, only possible while offline
+    Withdraw {
+        /// Amount in ether
+        amount: Ether,
+    },
+    /// Starts accepting new games
+    Online,
+    /// Stops accepting new games, only possible once no game is pending
+    Offline,
+    /// Sets the bet limits per hand, only possible while offline
+    SetLimits {
+        /// Smallest bet per hand, in ether
+        #[clap(long)]
+        min_bet: Ether,
+        /// Largest bet per hand, in ether
+        #[clap(long)]
+        max_bet: Ether,
+    },
+    /// Sets the fee per game, only possible while offline
+    SetFee {
+        /// Fee in ether
+        fee: Ether,
+    },
+    /// Commits to a freshly generated seed for the games started from now on. Seeds of games
+    /// started before are kept until the games are settled.
+    RotateCommitment,
+    /// Shows the dealer's record on the contract and its unsettled games
+    Status {
+        /// Block to look for the dealer's games from while no cursor is saved
+        #[clap(long, default_value_t = 0)]
+        from_block: u64,
+    },
+    /// Proves and settles the games that are ready, once. The games are read from the store, so
+    /// `serve` shouldn't be running at the same time.
+    Settle {
+        /// Block to look for the dealer's games from while no cursor is saved
+        #[clap(long, default_value_t = 0)]
+        from_block: u64,
+    },
+}
+
+#[derive(clap::Args, Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct ServeOptions {
+    /// Address the player API listens on [default: 0.0.0.0:3000]
+    #[clap(long, env = "HOST")]
+    host: Option<String>,
+    /// Block to follow the contract's events from while no cursor is saved [default: 0]
+    #[clap(long, env = "FROM_BLOCK")]
+    from_block: Option<u64>,
+    /// Largest payout sent ahead of the proof, in ether [default: 1]
+    #[clap(long, env = "MAX_PAYOUT")]
+    max_payout: Option<Ether>,
+    /// Free balance kept on the contract when paying out ahead of the proof, in ether
+    /// [default: 0]
+    #[clap(long, env = "PAYOUT_RESERVE")]
+    payout_reserve: Option<Ether>,
+    /// Free balance the dealer needs on the contract to start serving, in ether [default: enough
+    /// to lock one maximum bet]
+    #[clap(long, env = "MIN_BALANCE")]
+    min_balance: Option<Ether>,
+    /// Seconds between settlement rounds [default: 600]
+    #[clap(long, env = "SETTLE_INTERVAL")]
+    settle_interval: Option<u64>,
+    /// Seconds between rotations of the dealer seed, never rotated if not given
+    #[clap(long, env = "ROTATE_INTERVAL")]
+    rotate_interval: Option<u64>,
+    /// Maximum number of games proven together [default: 16]
+    #[clap(long, env = "MAX_BATCH")]
+    max_batch: Option<usize>,
+    /// Average block time of the chain in seconds [default: 12]
+    #[clap(long, env = "BLOCK_TIME")]
+    block_time: Option<u64>,
+}
+
+impl ServeOptions {
+    fn or(self, file: ServeOptions) -> ServeOptions {
+        ServeOptions {
+            host: self.host.or(file.host),
+            from_block: self.from_block.or(file.from_block),
+            max_payout: self.max_payout.or(file.max_payout),
+            payout_reserve: self.payout_reserve.or(file.payout_reserve),
+            min_balance: self.min_balance.or(file.min_balance),
+            settle_interval: self.settle_interval.or(file.settle_interval),
+            rotate_interval: self.rotate_interval.or(file.rotate_interval),
+            max_batch: self.max_batch.or(file.max_batch),
+            block_time: self.block_time.or(file.block_time),
+        }
+    }
+}
+
+/// Amount given in ether, e.g. `0.5`
+#[derive(Clone, Copy, Debug)]
+struct Ether(U256);
+
+impl FromStr for Ether {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let wei = ethers::utils::parse_ether(s).map_err(|err| err.to_string())?;
+        Ok(Ether(U256::from_limbs(wei.0)))
+    }
+}
+
+impl<'de> Deserialize<'de> for Ether {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+fn format_ether(amount: U256) -> String {
+    format!("{} ETH", ethers::utils::format_ether(ethers::types::U256(amount.into_limbs())))
+}
+
+fn settle_config(serve: &ServeOptions) -> SettleConfig {
+    SettleConfig {
+        interval: Duration::from_secs(serve.settle_interval.unwrap_or(600)),
+        max_batch: serve.max_batch.unwrap_or(16),
+        block_time: Duration::from_secs(serve.block_time.unwrap_or(12)),
+        proving_base: Duration::from_secs(300),
+        proving_per_game: Duration::from_secs(60),
+        safety_blocks: 50,
+        warn_blocks: 300,
+    }
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<()> {
     env_logger::init();
 
-    let eth = Arc::new(eth::Blockchain::new(
-        11155111,
-        "wss://sepolia.drpc.org",
-        "",
-        ""
-    ).await.unwrap());
+    let cli = Cli::parse();
+    let options = match &cli.config {
+        Some(path) => {
+            let file = std::fs::read_to_string(path)
+                .with_context(|| format!("reading {}", path.display()))?;
+            let file =
+                toml::from_str(&file).with_context(|| format!("parsing {}", path.display()))?;
+            cli.options.or(file)
+        }
+        None => cli.options,
+    };
+    let eth = Arc::new(options.connect().await?);
+
+    match cli.command {
+        Command::Serve(serve) => {
+            let seeds = Arc::new(options.seeds()?);
+            let store = Arc::new(options.store()?);
+            let archive = Arc::new(options.archive()?);
+            let cursor = options.cursor();
+            let serve = serve.or(options.serve.unwrap_or_default());
+
+            // a misconfigured dealer would only find out once its first proof reverts
+            let dealer = eth.dealers(eth.dealer()).await?;
+            // the contract locks twice the bet
+            let min_balance = serve.min_balance.map_or(dealer.max_bet * U256::from(2), |min| min.0);
+            let problems = dealer.problems(min_balance, seeds.get(&dealer.commitment.0).is_some());
+            if !problems.is_empty() {
+                anyhow::bail!(
+                    "refusing to serve as {}:\n  {}",
+                    eth.dealer(),
+                    problems.join("\n  ")
+                );
+            }
+            let games = Arc::new(store::restore(&*store, &seeds, eth.chain_id(), eth.contract())?);
+            let registry = Arc::new(GameRegistry::default());
+            let prepaid = Arc::new(Prepaid::restore(store.clone())?);
+            let (payouts, payouts_rx) = tokio::sync::mpsc::unbounded_channel();
+
+            tokio::task::spawn(eth::eth_task(
+                eth.clone(),
+                registry.clone(),
+                cursor.load()?.unwrap_or(serve.from_block.unwrap_or(0)),
+                cursor,
+            ));
+            tokio::task::spawn(settle::settle_task(
+                eth.clone(),
+                games.clone(),
+                registry.clone(),
+                prepaid.clone(),
+                store.clone(),
+                archive.clone(),
+                settle_config(&serve),
+            ));
+            tokio::task::spawn(payout::payout_task(
+                eth.clone(),
+                prepaid,
+                payouts_rx,
+                PayoutConfig {
+                    max_payout: serve
+                        .max_payout
+                        .map_or(U256::from(10).pow(U256::from(18)), |max| max.0),
+                    reserve: serve.payout_reserve.map_or(U256::ZERO, |reserve| reserve.0),
+                },
+            ));
+            if let Some(interval) = serve.rotate_interval {
+                tokio::task::spawn(seeds::seed_task(
+                    eth.clone(),
+                    seeds.clone(),
+                    registry.clone(),
+                    games.clone(),
+                    store.clone(),
+                    Duration::from_secs(interval),
+                ));
+            }
+            let host = serve.host.as_deref().unwrap_or("0.0.0.0:3000");
+            let state = AppState {
+                sm: games,
+                eth,
+                registry,
+                payouts: Some(payouts),
+                seeds,
+                store,
+                archive,
+            };
+            web::web_task(host, state).await;
+        }
+        Command::Register {
+            min_bet,
+            max_bet,
+            fee,
+            timeout_blocks,
+        } => {
+            let commitment = options.seeds()?.generate()?;
+            let tx_hash = eth
+                .register_dealer(min_bet.0, max_bet.0, fee.0, timeout_blocks, commitment)
+                .await?;
+            println!("registered {} in {:?}", eth.dealer(), tx_hash);
+        }
+        Command::Deposit {
+            amount,
+        } => {
+            let tx_hash = eth.deposit(amount.0).await?;
+            println!("deposited {} in {:?}", format_ether(amount.0), tx_hash);
+        }
+        Command::Withdraw {
+            amount,
+        } => {
+            let tx_hash = eth.withdraw(amount.0).await?;
+            println!("withdrew {} in {:?}", format_ether(amount.0), tx_hash);
+        }
+        Command::Online => {
+            let tx_hash = eth.go_online().await?;
+            println!("online in {:?}", tx_hash);
+        }
+        Command::Offline => {
+            let tx_hash = eth.go_offline().await?;
+            println!("offline in {:?}", tx_hash);
+        }
+        Command::SetLimits {
+            min_bet,
+            max_bet,
+        } => {
+            let tx_hash = eth.set_bet_limits(min_bet.0, max_bet.0).await?;
+            println!("bet limits set in {:?}", tx_hash);
+        }
+        Command::SetFee {
+            fee,
+        } => {
+            let tx_hash = eth.set_fee(fee.0).await?;
+            println!("fee set in {:?}", tx_hash);
+        }
+        Command::RotateCommitment => {
+            let commitment = options.seeds()?.rotate(&eth).await?;
+            println!("committed to {}", hex::encode(commitment));
+        }
+        Command::Status {
+            from_block,
+        } => {
+            let dealer = eth.dealers(eth.dealer()).await?;
+            println!("dealer      {}", eth.dealer());
+            if !dealer.registered() {
+                println!("registered  no");
+                return Ok(());
+            }
+            println!("online      {}", dealer.online);
+            println!("banned      {}", dealer.banned);
+            println!(
+                "balance     {} ({} locked)",
+                format_ether(dealer.balance),
+                format_ether(dealer.locked_balance)
+            );
+            println!(
+                "bets        {} to {}, fee {}",
+                format_ether(dealer.min_bet),
+                format_ether(dealer.max_bet),
+                format_ether(dealer.fee)
+            );
+            println!("timeout     {} blocks", dealer.timeout_blocks);
+            let stored = match options.seed_password {
+                Some(_) => match options.seeds()?.get(&dealer.commitment.0) {
+                    Some(_) => " (seed stored)",
+                    None => " (seed missing)",
+                },
+                None => "",
+            };
+            println!("commitment  {}{}", dealer.commitment, stored);
 
-    tokio::task::spawn(web::web_task("0.0.0.0:3000", eth, [0; 16]));
+            let registry = GameRegistry::default();
+            eth.sync_events(&registry, options.cursor().load()?.unwrap_or(from_block)).await?;
+            for game in registry.unfinished().await {
+                println!(
+                    "game {:<6}  started in block {}, reclaimable after block {}",
+                    game.start.game_index,
+                    game.start.block_number,
+                    game.start.block_number + dealer.timeout_blocks
+                );
+            }
+        }
+        Command::Settle {
+            from_block,
+        } => {
+            let registry = Arc::new(GameRegistry::default());
+            eth.sync_events(&registry, options.cursor().load()?.unwrap_or(from_block)).await?;
+            let seeds = options.seeds()?;
+            let store = Arc::new(options.store()?);
+            let mut games = store::restore(&*store, &seeds, eth.chain_id(), eth.contract())?;
+            settle::settle_once(
+                &eth,
+                &games,
+                registry.clone(),
+                Arc::new(Prepaid::restore(store.clone())?),
+                store,
+                Arc::new(options.archive()?),
+                &settle_config(&options.serve.unwrap_or_default()),
+            )
+            .await?;
+            for game in registry.unfinished().await {
+                let reason = if games.get_mut().contains_key(&game.start.game_index) {
+                    "isn't ready to be proven"
+                } else {
+                    "is missing from the store"
+                };
+                println!("game {} is unsettled, it {}", game.start.game_index, reason);
+            }
+        }
+    }
+    Ok(())
 }
@@ -1,20 +1,74 @@
-use alloy_primitives::{FixedBytes, U256};
-use alloy_sol_types::SolValue;
+use std::sync::Arc;
+use std::time::Duration;
+
+use alloy_primitives::{B256, U256};
+use alloy_sol_types::{
+    decode_revert_reason, sol, Revert, SolCall, SolError, SolEvent, SolInterface, SolValue,
+};
 use anyhow::{Context, Result};
 use ethers::middleware::SignerMiddleware;
-use ethers::providers::{Middleware, Provider, Ws};
+use ethers::providers::{Middleware, MiddlewareError, Provider, StreamExt, Ws};
 use ethers::signers::{LocalWallet, Signer, Wallet};
-use ethers::types::H256;
+use ethers::types::transaction::eip2718::TypedTransaction;
+use ethers::types::{Filter, Log, TransactionRequest, H256};
 use k256::ecdsa::VerifyingKey;
 use k256::EncodedPoint;
 
-// should prob monitor the chain for new events, but no time to implement that
-pub async fn eth_task() {
-    todo!()
+use crate::registry::{ChainEvent, GameRegistry, GameUpdate};
+use crate::sm::{ActionType, DeAction};
+use crate::store::BlockCursor;
+
+sol!("../contracts/IZkBlackjack.sol");
+
+use IZkBlackjack::{
+    dealersCall, depositCall, gameBetsCall, gamesCall, goOfflineCall, goOnlineCall,
+    onlineDealersCall, proveGamesCall, registerDealerCall, requestPlayerActionsCall,
+    setBetLimitsCall, setFeeCall, totalBetsCall, transferWinningsToUserCall, updateCommitmentCall,
+    withdrawCall, GameResult, GameStarted, HandDoubled, HandSplit, IZkBlackjackCalls,
+    PlayerActionsProvided, PlayerActionsRequested,
+};
+
+/// Most RPC providers cap the block range of `eth_getLogs`
+const LOG_BLOCK_RANGE: u64 = 1000;
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// Follows the contract's events from `from_block` on and feeds them into `registry`, saving the
+/// block to resume from in `cursor` as it goes.
+/// When the websocket drops, reconnects and resumes after the last processed block.
+pub async fn eth_task(
+    eth: Arc<Blockchain>,
+    registry: Arc<GameRegistry>,
+    from_block: u64,
+    cursor: BlockCursor,
+) {
+    let mut next_block = from_block;
+    let mut delay = Duration::from_secs(1);
+    loop {
+        let resumed_at = next_block;
+        match eth.follow_events(&registry, &cursor, &mut next_block).await {
+            Ok(()) => log::warn!("block subscription closed at block {}", next_block),
+            Err(err) => log::warn!("event monitor failed at block {}: {:?}", next_block, err),
+        }
+        if next_block > resumed_at {
+            delay = Duration::from_secs(1);
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+/// Saves the block to resume from, which stays at the start of the oldest unfinished game. A
+/// failure only costs rescanning more blocks after a restart.
+async fn save_cursor(registry: &GameRegistry, cursor: &BlockCursor) {
+    let block = registry.resume_block().await;
+    if let Err(err) = cursor.save(block) {
+        log::warn!("saving the event cursor failed: {:?}", err);
+    }
 }
 
 pub struct Blockchain {
     chain_id: u64,
+    rpc_url: String,
     client: SignerMiddleware<Provider<Ws>, Wallet<k256::ecdsa::SigningKey>>,
     contract: ethers::types::Address,
 }
@@ -33,60 +87,765 @@ impl Blockchain {
 
         Ok(Self {
             chain_id,
+            rpc_url: rpc_url.to_string(),
             client,
             contract,
         })
     }
 
-    // this is ugly af, but i don't care. it's 5am and i have 6.5 hours to finish this
-    pub async fn get_start_tx(&self, tx_hash: &str) -> Result<StartData> {
-        let tx = self
+    pub fn chain_id(&self) -> u64 {
+        self.chain_id
+    }
+
+    /// Address of the `ZkBlackjack` contract
+    pub fn contract(&self) -> alloy_primitives::Address {
+        alloy_primitives::Address::from(self.contract.0)
+    }
+
+    /// Address of the dealer, the account sending the transactions
+    pub fn dealer(&self) -> alloy_primitives::Address {
+        alloy_primitives::Address::from(self.client.address().0)
+    }
+
+    pub async fn block_number(&self) -> Result<u64> {
+        Ok(self.client.get_block_number().await?.as_u64())
+    }
+
+    /// Number of blocks after its start at which the player can reclaim a game of this dealer
+    pub async fn timeout_blocks(&self) -> Result<u64> {
+        Ok(self.dealers(self.dealer()).await?.timeout_blocks)
+    }
+
+    /// Balance of the dealer on the contract that isn't locked in games
+    pub async fn free_balance(&self) -> Result<U256> {
+        Ok(self.dealers(self.dealer()).await?.free_balance())
+    }
+
+    /// A dealer as registered on the contract, all zero if it never registered
+    pub async fn dealers(&self, dealer: alloy_primitives::Address) -> Result<Dealer> {
+        let dealer = self
+            .view(dealersCall {
+                _0: dealer,
+            })
+            .await?;
+        Ok(Dealer {
+            addr: dealer.addr,
+            online: dealer.online,
+            balance: dealer.balance,
+            locked_balance: dealer.lockedBalance,
+            commitment: dealer.commitment,
+            min_bet: dealer.minBet,
+            max_bet: dealer.maxBet,
+            fee: dealer.fee,
+            banned: dealer.banned,
+            timeout_blocks: dealer.timeoutBlocks.try_into()?,
+        })
+    }
+
+    /// A game as stored by the contract, all zero if it was never started
+    pub async fn games(&self, game_id: u64) -> Result<Game> {
+        let game = self
+            .view(gamesCall {
+                _0: U256::from(game_id),
+            })
+            .await?;
+        let bets = self
+            .view(gameBetsCall {
+                gameId: U256::from(game_id),
+            })
+            .await?
+            ._0;
+        let actions_requested_block: u64 = game.playerActionsRequestedBlockNumber.try_into()?;
+        Ok(Game {
+            finished: game.finished,
+            dealer: game.dealer,
+            player: game.player,
+            dealer_commitment: game.dealerCommitment,
+            player_commitment: game.playerCommitment,
+            player_pubkey: game.playerPublicKey.to_vec(),
+            player_win: game.playerWin,
+            bets,
+            start_block: game.gameStartBlock.try_into()?,
+            actions_requested_block: (actions_requested_block != 0)
+                .then_some(actions_requested_block),
+            actions_hash: (!game.playerActionsHash.is_zero()).then_some(game.playerActionsHash),
+        })
+    }
+
+    /// Dealers currently accepting new games
+    pub async fn online_dealers(&self) -> Result<Vec<alloy_primitives::Address>> {
+        // the getter takes an index and reverts past the end of the array
+        let mut dealers = Vec::new();
+        loop {
+            match self
+                .view(onlineDealersCall {
+                    _0: U256::from(dealers.len()),
+                })
+                .await
+            {
+                Ok(dealer) => dealers.push(dealer._0),
+                Err(err) if err.is::<Reverted>() => return Ok(dealers),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Sum of the current bets of all hands of a game
+    pub async fn total_bets(&self, game_id: u64) -> Result<U256> {
+        Ok(self
+            .view(totalBetsCall {
+                gameId: U256::from(game_id),
+            })
+            .await?
+            ._0)
+    }
+
+    /// Calls a view function of the contract
+    async fn view<C: SolCall>(&self, call: C) -> Result<C::Return> {
+        let tx = TransactionRequest::new().to(self.contract).data(call.abi_encode());
+        let data = self.client.call(&tx.into(), None).await.map_err(reverted::<C, _>)?;
+        Ok(C::abi_decode_returns(&data, true)?)
+    }
+
+    /// Reads every game of this dealer started by a transaction. Besides direct `startGame`
+    /// calls, this finds games started through smart-contract wallets and multicalls.
+    pub async fn get_start_tx(&self, tx_hash: &str) -> Result<Vec<StartData>> {
+        let receipt = self
             .client
             .get_transaction_receipt(tx_hash.parse::<H256>()?)
             .await?
-            .context("get_transaction")?;
-        if tx.logs.len() != 1 {
-            anyhow::bail!("invalid logs length");
-        }
-        let log = &tx.logs[0];
-        if log.address != self.contract {
-            anyhow::bail!("invalid contract address");
-        }
-        let log = log.data.clone();
-        let calldata = match tx.other.get("input").context("input")? {
-            serde_json::Value::String(data) => hex::decode(data)?,
-            _ => anyhow::bail!("invalid input"),
+            .context("get_transaction_receipt")?;
+        if receipt.status != Some(1.into()) {
+            anyhow::bail!("transaction reverted");
+        }
+
+        let mut starts = Vec::new();
+        for log in &receipt.logs {
+            if log.address != self.contract
+                || log.topics.first() != Some(&H256(GameStarted::SIGNATURE_HASH.0))
+            {
+                continue;
+            }
+            let topics = log.topics.iter().map(|topic| B256::from(topic.0));
+            let event = GameStarted::decode_raw_log(topics, &log.data, true)?;
+            if event.dealer == self.dealer() {
+                starts.push(start_data(event, log)?);
+            }
+        }
+        if starts.is_empty() {
+            anyhow::bail!("no game of this dealer was started");
+        }
+        Ok(starts)
+    }
+
+    /// Registers the account as a dealer. Only accounts whitelisted by the register authority can
+    /// register.
+    pub async fn register_dealer(
+        &self,
+        min_bet: U256,
+        max_bet: U256,
+        fee: U256,
+        timeout_blocks: u64,
+        commitment: [u8; 32],
+    ) -> Result<H256> {
+        self.send(registerDealerCall {
+            _minBet: min_bet,
+            _maxBet: max_bet,
+            _fee: fee,
+            _timeoutBlocks: U256::from(timeout_blocks),
+            _commitment: commitment.into(),
+        })
+        .await
+    }
+
+    /// Commits to the seed dealing the games started from now on
+    pub async fn update_commitment(&self, commitment: [u8; 32]) -> Result<H256> {
+        self.send(updateCommitmentCall {
+            _commitment: commitment.into(),
+        })
+        .await
+    }
+
+    /// Only possible while offline
+    pub async fn set_fee(&self, fee: U256) -> Result<H256> {
+        self.send(setFeeCall {
+            _fee: fee,
+        })
+        .await
+    }
+
+    /// Only possible while offline
+    pub async fn set_bet_limits(&self, min_bet: U256, max_bet: U256) -> Result<H256> {
+        self.send(setBetLimitsCall {
+            _minBet: min_bet,
+            _maxBet: max_bet,
+        })
+        .await
+    }
+
+    /// Starts accepting new games
+    pub async fn go_online(&self) -> Result<H256> {
+        self.send(goOnlineCall {}).await
+    }
+
+    /// Stops accepting new games, only possible once no game of the dealer is pending
+    pub async fn go_offline(&self) -> Result<H256> {
+        self.send(goOfflineCall {}).await
+    }
+
+    /// Adds to the dealer's bankroll, only possible while offline
+    pub async fn deposit(&self, amount: U256) -> Result<H256> {
+        self.send_value(depositCall {}, amount).await
+    }
+
+    /// Takes out of the dealer's bankroll, only possible while offline
+    pub async fn withdraw(&self, amount: U256) -> Result<H256> {
+        self.send(withdrawCall {
+            _amount: amount,
+        })
+        .await
+    }
+
+    /// Submits the proof of a guest's journal with `proveGames` and waits for it to be mined
+    pub async fn prove_games(&self, journal: &[u8], seal: Vec<u8>) -> Result<H256> {
+        let output = IZkBlackjack::Output::abi_decode(journal, true)?;
+        let call = proveGamesCall {
+            _gameIds: output.gameIds.clone(),
+            _output: output,
+            _seal: seal.into(),
         };
-        let (_player, game_index, _dealer) =
-            <(alloy_primitives::Address, U256, alloy_primitives::Address)>::abi_decode(&log, true)?;
-
-        let (dealer, init_bets, player_commitment, player_pubkey) = <(
-            alloy_primitives::Address,
-            Vec<U256>,
-            FixedBytes<32>,
-            alloy_primitives::Bytes,
-        )>::abi_decode(
-            &calldata, true
-        )?;
-
-        if dealer.0.as_slice() != self.client.address().as_bytes() {
-            anyhow::bail!("invalid dealer");
-        }
-
-        Ok(StartData {
-            bets: init_bets,
-            player_commitment: player_commitment.0.to_vec(),
-            player_pubkey: VerifyingKey::from_encoded_point(&EncodedPoint::from_bytes(
-                &player_pubkey.to_vec(),
-            )?)?,
-            game_index: game_index.as_limbs()[0],
+        self.send(call).await
+    }
+
+    /// Pays out a game's winnings ahead of its proof with `transferWinningsToUser`
+    pub async fn transfer_winnings(&self, game_id: u64, payout: U256) -> Result<H256> {
+        self.send(transferWinningsToUserCall {
+            _gameId: U256::from(game_id),
+            _payout: payout,
         })
+        .await
+    }
+
+    /// Asks the player of an abandoned game to submit their actions on chain
+    pub async fn request_player_actions(&self, game_id: u64) -> Result<H256> {
+        self.send(requestPlayerActionsCall {
+            _gameId: U256::from(game_id),
+        })
+        .await
+    }
+
+    /// Sends a transaction calling the contract and waits for it to be mined
+    async fn send<C: SolCall>(&self, call: C) -> Result<H256> {
+        self.send_value(call, U256::ZERO).await
+    }
+
+    /// Sends a transaction calling the contract with `value` attached and waits for it to be
+    /// mined
+    async fn send_value<C: SolCall>(&self, call: C, value: U256) -> Result<H256> {
+        let tx: TypedTransaction = TransactionRequest::new()
+            .to(self.contract)
+            .data(call.abi_encode())
+            .value(ethers::types::U256(value.into_limbs()))
+            .into();
+        // estimating the gas runs the call, most reverts are caught here with their reason
+        let pending =
+            self.client.send_transaction(tx.clone(), None).await.map_err(reverted::<C, _>)?;
+        let tx_hash = pending.tx_hash();
+        let receipt = pending.await?.with_context(|| format!("{} dropped", C::SIGNATURE))?;
+        if receipt.status != Some(1.into()) {
+            // the receipt has no reason, replaying the call on the state of its block usually
+            // reverts the same way
+            let reason = match self.client.call(&tx, receipt.block_number.map(Into::into)).await {
+                Err(err) => revert_reason(&err),
+                Ok(_) => None,
+            };
+            return Err(Reverted {
+                call: C::SIGNATURE,
+                reason: reason.unwrap_or_else(|| format!("transaction {:?}", tx_hash)),
+            }
+            .into());
+        }
+        Ok(tx_hash)
+    }
+
+    /// Reads every `double` and `split` paid by a transaction, from the events the contract
+    /// emitted. Besides direct calls, this finds payments made through smart-contract wallets and
+    /// multicalls.
+    pub async fn get_payment_tx(&self, tx_hash: &str) -> Result<Vec<PaymentData>> {
+        let tx_hash = tx_hash.parse::<H256>()?;
+        let receipt = self
+            .client
+            .get_transaction_receipt(tx_hash)
+            .await?
+            .context("get_transaction_receipt")?;
+        if receipt.status != Some(1.into()) {
+            anyhow::bail!("transaction reverted");
+        }
+
+        let mut payments = Vec::new();
+        for log in &receipt.logs {
+            if log.address != self.contract {
+                continue;
+            }
+            let topics = log.topics.iter().map(|topic| B256::from(topic.0));
+            let (game_id, hand, kind, value) = match log.topics.first().map(|topic| topic.0.into())
+            {
+                Some(HandDoubled::SIGNATURE_HASH) => {
+                    let event = HandDoubled::decode_raw_log(topics, &log.data, true)?;
+                    (event.gameId, event.handIndex, ActionType::Double, event.amount)
+                }
+                Some(HandSplit::SIGNATURE_HASH) => {
+                    let event = HandSplit::decode_raw_log(topics, &log.data, true)?;
+                    (event.gameId, event.handIndex, ActionType::Split, event.amount)
+                }
+                _ => continue,
+            };
+            payments.push(PaymentData {
+                tx_hash: B256::from(tx_hash.0),
+                game_id: game_id.try_into()?,
+                hand,
+                kind,
+                value,
+            });
+        }
+        if payments.is_empty() {
+            anyhow::bail!("no double or split was paid");
+        }
+        Ok(payments)
+    }
+
+    /// Feeds the events of blocks `from_block..=head` into `registry` once, returning the next
+    /// block to process
+    pub async fn sync_events(&self, registry: &GameRegistry, from_block: u64) -> Result<u64> {
+        let provider = Provider::<Ws>::connect(&self.rpc_url).await?;
+        let head = provider.get_block_number().await?.as_u64();
+        let mut next_block = from_block;
+        self.process_blocks(&provider, registry, &mut next_block, head).await?;
+        self.sync_finished(registry).await?;
+        Ok(next_block)
+    }
+
+    /// Marks the unfinished games of `registry` that the contract has finished. Games that
+    /// `proveGames` settled without paying out anything beyond the prepaid part leave no event.
+    async fn sync_finished(&self, registry: &GameRegistry) -> Result<()> {
+        for game in registry.unfinished().await {
+            let game_id = game.start.game_index;
+            let game = self
+                .view(gamesCall {
+                    _0: U256::from(game_id),
+                })
+                .await?;
+            if game.finished {
+                registry.finish(game_id).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Processes blocks on a fresh connection until the subscription ends or fails
+    async fn follow_events(
+        &self,
+        registry: &GameRegistry,
+        cursor: &BlockCursor,
+        next_block: &mut u64,
+    ) -> Result<()> {
+        let provider = Provider::<Ws>::connect(&self.rpc_url).await?;
+        let mut blocks = provider.subscribe_blocks().await?;
+        // catch up on everything that happened while disconnected
+        let head = provider.get_block_number().await?.as_u64();
+        self.process_blocks(&provider, registry, next_block, head).await?;
+        self.sync_finished(registry).await?;
+        save_cursor(registry, cursor).await;
+        while let Some(block) = blocks.next().await {
+            let Some(number) = block.number else {
+                continue;
+            };
+            self.process_blocks(&provider, registry, next_block, number.as_u64()).await?;
+            save_cursor(registry, cursor).await;
+        }
+        Ok(())
+    }
+
+    /// Feeds the events of blocks `next_block..=to` into `registry`, advancing `next_block` as it
+    /// goes so that a failure can resume where it stopped
+    async fn process_blocks(
+        &self,
+        provider: &Provider<Ws>,
+        registry: &GameRegistry,
+        next_block: &mut u64,
+        to: u64,
+    ) -> Result<()> {
+        while *next_block <= to {
+            let end = to.min(*next_block + LOG_BLOCK_RANGE - 1);
+            let filter =
+                Filter::new().address(self.contract).from_block(*next_block).to_block(end).topic0(
+                    [
+                        GameStarted::SIGNATURE_HASH,
+                        GameResult::SIGNATURE_HASH,
+                        HandDoubled::SIGNATURE_HASH,
+                        HandSplit::SIGNATURE_HASH,
+                        PlayerActionsRequested::SIGNATURE_HASH,
+                        PlayerActionsProvided::SIGNATURE_HASH,
+                    ]
+                    .map(|topic| H256(topic.0))
+                    .to_vec(),
+                );
+            // the whole range is read before anything is applied, so a range that fails halfway
+            // is retried without applying its first events twice
+            let mut events = Vec::new();
+            for log in provider.get_logs(&filter).await? {
+                let mut event = match self.decode_event(&log) {
+                    Ok(Some(event)) => event,
+                    Ok(None) => continue,
+                    Err(err) => {
+                        log::warn!("skipping log {:?}: {:?}", log.transaction_hash, err);
+                        continue;
+                    }
+                };
+                if let ChainEvent::Update {
+                    game_id,
+                    update:
+                        GameUpdate::ActionsProvided {
+                            actions,
+                            ..
+                        },
+                } = &mut event
+                {
+                    *actions = self.provided_actions(provider, &log).await?;
+                    if actions.is_none() {
+                        log::warn!("can't decode actions of game {}", game_id);
+                    }
+                }
+                events.push(event);
+            }
+            for event in events {
+                registry.apply(event).await;
+            }
+            *next_block = end + 1;
+            registry.processed(*next_block);
+        }
+        Ok(())
+    }
+
+    /// Decodes a log of the contract. Returns `None` for games of other dealers. Provided actions
+    /// aren't part of the log, they are left `None`.
+    fn decode_event(&self, log: &Log) -> Result<Option<ChainEvent>> {
+        let topics = log.topics.iter().map(|topic| B256::from(topic.0));
+        let block_number = log.block_number.context("pending log")?.as_u64();
+        let topic0 = B256::from(log.topics.first().context("anonymous log")?.0);
+
+        let event = match topic0 {
+            GameStarted::SIGNATURE_HASH => {
+                let event = GameStarted::decode_raw_log(topics, &log.data, true)?;
+                if event.dealer != self.dealer() {
+                    return Ok(None);
+                }
+                ChainEvent::GameStarted(start_data(event, log)?)
+            }
+            GameResult::SIGNATURE_HASH => {
+                let event = GameResult::decode_raw_log(topics, &log.data, true)?;
+                ChainEvent::Update {
+                    game_id: event.gameId.try_into()?,
+                    update: GameUpdate::Result {
+                        payout: event.playerWin,
+                    },
+                }
+            }
+            HandDoubled::SIGNATURE_HASH => {
+                let event = HandDoubled::decode_raw_log(topics, &log.data, true)?;
+                ChainEvent::Update {
+                    game_id: event.gameId.try_into()?,
+                    update: GameUpdate::Doubled {
+                        hand: event.handIndex,
+                        amount: event.amount,
+                    },
+                }
+            }
+            HandSplit::SIGNATURE_HASH => {
+                let event = HandSplit::decode_raw_log(topics, &log.data, true)?;
+                ChainEvent::Update {
+                    game_id: event.gameId.try_into()?,
+                    update: GameUpdate::Split {
+                        hand: event.handIndex,
+                        amount: event.amount,
+                    },
+                }
+            }
+            PlayerActionsRequested::SIGNATURE_HASH => {
+                let event = PlayerActionsRequested::decode_raw_log(topics, &log.data, true)?;
+                ChainEvent::Update {
+                    game_id: event.gameId.try_into()?,
+                    update: GameUpdate::ActionsRequested {
+                        block: block_number,
+                    },
+                }
+            }
+            PlayerActionsProvided::SIGNATURE_HASH => {
+                let event = PlayerActionsProvided::decode_raw_log(topics, &log.data, true)?;
+                ChainEvent::Update {
+                    game_id: event.gameId.try_into()?,
+                    update: GameUpdate::ActionsProvided {
+                        actions_hash: event.actionsHash,
+                        actions: None,
+                    },
+                }
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(event))
+    }
+
+    /// Actions submitted with the `provideActions` call that emitted `log`, `None` if the
+    /// transaction isn't a direct `provideActions` call. Fails only if the transaction can't be
+    /// fetched.
+    async fn provided_actions(
+        &self,
+        provider: &Provider<Ws>,
+        log: &Log,
+    ) -> Result<Option<Vec<DeAction>>> {
+        let tx_hash = log.transaction_hash.context("log without transaction")?;
+        let tx = provider.get_transaction(tx_hash).await?.context("get_transaction")?;
+        let Ok(IZkBlackjackCalls::provideActions(call)) =
+            IZkBlackjackCalls::abi_decode(&tx.input, true)
+        else {
+            return Ok(None);
+        };
+        Ok(Some(
+            call._actions
+                .into_iter()
+                .map(|action| DeAction {
+                    nonce: action.nonce,
+                    handId: action.handId,
+                    inner: action.inner,
+                    my_cards: action.my_cards,
+                    dealer_cards: action.dealer_cards,
+                })
+                .collect(),
+        ))
     }
 }
 
+/// Reason the contract gave for reverting, if `err` is a revert
+fn revert_reason(err: &impl MiddlewareError) -> Option<String> {
+    let response = err.as_error_response()?;
+    let data = response.as_revert_data()?;
+    let reason = match Revert::abi_decode(&data, true) {
+        Ok(revert) => revert.reason,
+        Err(_) => decode_revert_reason(&data).unwrap_or_else(|| response.message.clone()),
+    };
+    Some(reason)
+}
+
+/// Turns a revert of `C` into [`Reverted`], leaving other errors as they are
+fn reverted<C: SolCall, E: MiddlewareError + 'static>(err: E) -> anyhow::Error {
+    match revert_reason(&err) {
+        Some(reason) => Reverted {
+            call: C::SIGNATURE,
+            reason,
+        }
+        .into(),
+        None => err.into(),
+    }
+}
+
+/// Reads a `GameStarted` event of the contract. The event carries everything the game is dealt
+/// with, so no state has to be read at the start block.
+fn start_data(event: GameStarted, log: &Log) -> Result<StartData> {
+    Ok(StartData {
+        player: event.player,
+        block_number: log.block_number.context("pending log")?.as_u64(),
+        bets: event.bets,
+        dealer_commitment: event.dealerCommitment,
+        player_commitment: event.playerCommitment.to_vec(),
+        player_pubkey: VerifyingKey::from_encoded_point(&EncodedPoint::from_bytes(
+            &event.playerPublicKey,
+        )?)?,
+        game_index: event.gameId.try_into()?,
+    })
+}
+
+#[derive(Clone, Debug)]
 pub struct StartData {
+    pub player: alloy_primitives::Address,
+    /// Block the game was started in
+    pub block_number: u64,
     pub bets: Vec<U256>,
+    /// Commitment of the seed the game has to be dealt with
+    pub dealer_commitment: B256,
     pub player_commitment: Vec<u8>,
     pub player_pubkey: VerifyingKey,
     pub game_index: u64,
 }
+
+/// A dealer as registered on the contract
+#[derive(Clone, Debug)]
+pub struct Dealer {
+    pub addr: alloy_primitives::Address,
+    pub online: bool,
+    pub balance: U256,
+    /// Part of the balance backing the bets of pending games
+    pub locked_balance: U256,
+    /// Commitment to the seed dealing new games
+    pub commitment: B256,
+    pub min_bet: U256,
+    pub max_bet: U256,
+    pub fee: U256,
+    pub banned: bool,
+    pub timeout_blocks: u64,
+}
+
+impl Dealer {
+    pub fn registered(&self) -> bool {
+        !self.addr.is_zero()
+    }
+
+    /// Balance that isn't locked in games
+    pub fn free_balance(&self) -> U256 {
+        self.balance.saturating_sub(self.locked_balance)
+    }
+
+    /// Everything that keeps the dealer from serving games. `min_free_balance` is the floor of
+    /// the free balance, `seed_stored` whether the seed of the on-chain commitment is at hand.
+    pub fn problems(&self, min_free_balance: U256, seed_stored: bool) -> Vec<String> {
+        if !self.registered() {
+            return vec!["the account isn't registered as a dealer".to_string()];
+        }
+        let mut problems = Vec::new();
+        if self.banned {
+            problems.push("the dealer is banned, it can't start new games".to_string());
+        }
+        if !self.online {
+            problems.push("the dealer is offline, players can't start games".to_string());
+        }
+        if self.free_balance() < min_free_balance {
+            problems.push(format!(
+                "the free balance of {} wei is below the floor of {} wei",
+                self.free_balance(),
+                min_free_balance
+            ));
+        }
+        if !seed_stored {
+            problems.push(format!(
+                "no stored seed matches the on-chain commitment {}, every proof would revert \
+                 with `invalid proof dealer commitment`",
+                self.commitment
+            ));
+        }
+        problems
+    }
+}
+
+/// A game as stored by the contract
+#[derive(Clone, Debug)]
+pub struct Game {
+    pub finished: bool,
+    pub dealer: alloy_primitives::Address,
+    pub player: alloy_primitives::Address,
+    pub dealer_commitment: B256,
+    pub player_commitment: B256,
+    pub player_pubkey: Vec<u8>,
+    /// Amount paid out to the player so far
+    pub player_win: U256,
+    /// Current bet of every hand, including doubles and splits
+    pub bets: Vec<U256>,
+    pub start_block: u64,
+    /// Block in which the dealer called `requestPlayerActions`
+    pub actions_requested_block: Option<u64>,
+    /// Hash of the actions the player submitted with `provideActions`
+    pub actions_hash: Option<B256>,
+}
+
+/// The contract rejected a call
+#[derive(Debug)]
+pub struct Reverted {
+    /// Signature of the function called
+    pub call: &'static str,
+    pub reason: String,
+}
+
+impl std::fmt::Display for Reverted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} reverted: {}", self.call, self.reason)
+    }
+}
+
+impl std::error::Error for Reverted {}
+
+/// Payment of the player for doubling or splitting a hand
+pub struct PaymentData {
+    pub tx_hash: B256,
+    pub game_id: u64,
+    pub hand: u8,
+    pub kind: ActionType,
+    pub value: U256,
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::providers::{JsonRpcError, ProviderError, WsClientError};
+
+    use super::*;
+
+    fn rpc_error(code: i64, message: &str, data: Option<&[u8]>) -> ProviderError {
+        ProviderError::JsonRpcClientError(Box::new(WsClientError::JsonRpcError(JsonRpcError {
+            code,
+            message: message.to_string(),
+            data: data.map(|data| serde_json::Value::String(format!("0x{}", hex::encode(data)))),
+        })))
+    }
+
+    fn dealer() -> Dealer {
+        Dealer {
+            addr: alloy_primitives::Address::repeat_byte(1),
+            online: true,
+            balance: U256::from(10),
+            locked_balance: U256::from(4),
+            commitment: B256::repeat_byte(2),
+            min_bet: U256::from(1),
+            max_bet: U256::from(2),
+            fee: U256::ZERO,
+            banned: false,
+            timeout_blocks: 1000,
+        }
+    }
+
+    #[test]
+    fn test_dealer_problems() {
+        assert!(dealer().problems(U256::from(6), true).is_empty());
+        assert_eq!(dealer().problems(U256::from(7), true).len(), 1);
+
+        let unregistered = Dealer {
+            addr: alloy_primitives::Address::ZERO,
+            ..dealer()
+        };
+        assert_eq!(unregistered.problems(U256::ZERO, false).len(), 1);
+
+        let banned = Dealer {
+            online: false,
+            banned: true,
+            ..dealer()
+        };
+        let problems = banned.problems(U256::ZERO, false);
+        assert_eq!(problems.len(), 3);
+        assert!(problems[2].contains("invalid proof dealer commitment"));
+    }
+
+    #[test]
+    fn test_revert_reason() {
+        let data = Revert::from("dealer is online").abi_encode();
+        let err = reverted::<goOnlineCall, _>(rpc_error(3, "execution reverted", Some(&data)));
+        let err = err.downcast::<Reverted>().unwrap();
+        assert_eq!(err.to_string(), "goOnline() reverted: dealer is online");
+
+        // out of bounds array getters revert with a panic
+        let err = reverted::<onlineDealersCall, _>(rpc_error(
+            3,
+            "execution reverted",
+            Some(&alloy_sol_types::Panic::from(0x32).abi_encode()),
+        ));
+        assert!(err.is::<Reverted>());
+
+        let err = reverted::<goOnlineCall, _>(rpc_error(-32000, "nonce too low", None));
+        assert!(!err.is::<Reverted>());
+    }
+}
@@ -1,4 +1,10 @@
+pub mod archive;
 pub mod eth;
-pub mod web;
-pub mod sm;
+pub mod payout;
 pub mod r0;
+pub mod registry;
+pub mod seeds;
+pub mod settle;
+pub mod sm;
+pub mod store;
+pub mod web;
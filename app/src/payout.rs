@@ -0,0 +1,105 @@
+//! Paying out winnings right away with `transferWinningsToUser`, ahead of the proof.
+//! `proveGames` later only transfers whatever the proven payout exceeds the prepaid amount by.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use alloy_primitives::U256;
+use anyhow::Result;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::RwLock;
+
+use crate::eth::Blockchain;
+use crate::store::GameStore;
+
+pub struct PayoutConfig {
+    /// Largest payout sent ahead of the proof, bigger ones wait for settlement
+    pub max_payout: U256,
+    /// Free balance the dealer keeps on the contract after paying out
+    pub reserve: U256,
+}
+
+/// Winnings of a terminated game
+#[derive(Debug)]
+pub struct Payout {
+    pub game_id: u64,
+    pub amount: U256,
+}
+
+/// Winnings paid out ahead of the proof, by game id. Recorded in the game store, so that a restart
+/// still knows what was paid.
+pub struct Prepaid {
+    paid: RwLock<HashMap<u64, U256>>,
+    store: Arc<dyn GameStore>,
+}
+
+impl Prepaid {
+    /// Reads the winnings paid out for the unsettled games of `store`
+    pub fn restore(store: Arc<dyn GameStore>) -> Result<Self> {
+        let paid = store
+            .load()?
+            .into_iter()
+            .filter_map(|game| Some((game.game_id, game.prepaid?)))
+            .collect();
+        Ok(Self {
+            paid: RwLock::new(paid),
+            store,
+        })
+    }
+
+    pub async fn get(&self, game_id: u64) -> Option<U256> {
+        self.paid.read().await.get(&game_id).copied()
+    }
+
+    /// Forgets a settled game, returning what was paid out for it ahead of the proof
+    pub async fn settle(&self, game_id: u64) -> U256 {
+        self.paid.write().await.remove(&game_id).unwrap_or_default()
+    }
+}
+
+/// Pays out the winnings received on `payouts` as long as they are within the cap and the
+/// dealer's bankroll. Anything not paid here is paid by `proveGames`.
+pub async fn payout_task(
+    eth: Arc<Blockchain>,
+    prepaid: Arc<Prepaid>,
+    mut payouts: UnboundedReceiver<Payout>,
+    config: PayoutConfig,
+) {
+    while let Some(payout) = payouts.recv().await {
+        if let Err(err) = pay(&eth, &prepaid, &payout, &config).await {
+            log::warn!(
+                "paying out game {} failed, leaving it to the proof: {:?}",
+                payout.game_id,
+                err
+            );
+        }
+    }
+}
+
+async fn pay(
+    eth: &Blockchain,
+    prepaid: &Prepaid,
+    payout: &Payout,
+    config: &PayoutConfig,
+) -> Result<()> {
+    if payout.amount.is_zero() || prepaid.get(payout.game_id).await.is_some() {
+        return Ok(());
+    }
+    if payout.amount > config.max_payout {
+        log::info!("payout of game {} is above the cap, leaving it to the proof", payout.game_id);
+        return Ok(());
+    }
+    let free_balance = eth.free_balance().await?;
+    if free_balance < payout.amount + config.reserve {
+        anyhow::bail!("free balance of {} is too low", free_balance);
+    }
+
+    let tx_hash = eth.transfer_winnings(payout.game_id, payout.amount).await?;
+    prepaid.paid.write().await.insert(payout.game_id, payout.amount);
+    // the contract refuses a second transfer anyway, the record only keeps the report right
+    if let Err(err) = prepaid.store.prepaid(payout.game_id, payout.amount) {
+        log::warn!("recording the payout of game {} failed: {:?}", payout.game_id, err);
+    }
+    log::info!("paid out {} for game {} in {:?}", payout.amount, payout.game_id, tx_hash);
+    Ok(())
+}
@@ -1,4 +1,5 @@
 use crate::sm::{GameInput, Input};
+use alloy_primitives::{Address, U256};
 use alloy_sol_types::SolValue;
 use anyhow::Result;
 use methods::BLACKJACK_ELF;
@@ -8,10 +9,14 @@ use risc0_zkvm::{default_prover, ExecutorEnv, ProverOpts, VerifierContext};
 pub fn prove_inner(
     game_inputs: Vec<GameInput>,
     dealer_seed: [u8; 16],
+    chain_id: u64,
+    contract: Address,
 ) -> Result<(Vec<u8>, Vec<u8>)> {
     let input = Input {
         games: game_inputs,
         dealerSeed: dealer_seed.into(),
+        chainId: U256::from(chain_id),
+        verifyingContract: contract,
     };
     let input = input.abi_encode();
     let env = ExecutorEnv::builder().write_slice(&input).build()?;
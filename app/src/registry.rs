@@ -0,0 +1,201 @@
+//! Games of this dealer as recorded by the contract
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use alloy_primitives::{B256, U256};
+use tokio::sync::RwLock;
+
+use crate::eth::StartData;
+use crate::sm::DeAction;
+
+/// On-chain state of a game
+#[derive(Clone, Debug)]
+pub struct ChainGame {
+    pub start: StartData,
+    /// `(hand, amount)` of every `double` the player paid for, in order
+    pub doubles: Vec<(u8, U256)>,
+    /// `(hand, amount)` of every `split` the player paid for, in order
+    pub splits: Vec<(u8, U256)>,
+    /// Block in which the dealer called `requestPlayerActions`
+    pub actions_requested_block: Option<u64>,
+    /// Hash of the actions the player submitted with `provideActions`
+    pub actions_hash: Option<B256>,
+    /// The submitted actions themselves, unless the call couldn't be decoded
+    pub provided_actions: Option<Vec<DeAction>>,
+    /// Amount paid out to the player by the `GameResult` that finished the game. `proveGames`
+    /// only emits it for games that pay more than was prepaid, so it can be missing from a
+    /// finished game.
+    pub payout: Option<U256>,
+    finished: bool,
+}
+
+impl ChainGame {
+    pub fn new(start: StartData) -> Self {
+        Self {
+            start,
+            doubles: Vec::new(),
+            splits: Vec::new(),
+            actions_requested_block: None,
+            actions_hash: None,
+            provided_actions: None,
+            payout: None,
+            finished: false,
+        }
+    }
+
+    /// Whether the contract finished the game, i.e. it was settled or reclaimed
+    pub fn finished(&self) -> bool {
+        self.finished
+    }
+
+    fn apply(&mut self, update: GameUpdate) {
+        match update {
+            GameUpdate::Result {
+                payout,
+            } => {
+                self.payout = Some(payout);
+                self.finished = true;
+            }
+            GameUpdate::Finished => self.finished = true,
+            GameUpdate::Doubled {
+                hand,
+                amount,
+            } => self.doubles.push((hand, amount)),
+            GameUpdate::Split {
+                hand,
+                amount,
+            } => self.splits.push((hand, amount)),
+            GameUpdate::ActionsRequested {
+                block,
+            } => self.actions_requested_block = Some(block),
+            GameUpdate::ActionsProvided {
+                actions_hash,
+                actions,
+            } => {
+                self.actions_hash = Some(actions_hash);
+                self.provided_actions = actions;
+            }
+        }
+    }
+}
+
+/// Contract events relevant to the dealer's games
+#[derive(Clone, Debug)]
+pub enum ChainEvent {
+    GameStarted(StartData),
+    /// Any other event, these only concern games that are already known
+    Update {
+        game_id: u64,
+        update: GameUpdate,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub enum GameUpdate {
+    Result {
+        payout: U256,
+    },
+    /// The game is finished without a `GameResult`, as the contract's `finished` flag or a
+    /// successful `proveGames` shows
+    Finished,
+    Doubled {
+        hand: u8,
+        amount: U256,
+    },
+    Split {
+        hand: u8,
+        amount: U256,
+    },
+    ActionsRequested {
+        block: u64,
+    },
+    ActionsProvided {
+        actions_hash: B256,
+        actions: Option<Vec<DeAction>>,
+    },
+}
+
+#[derive(Default)]
+pub struct GameRegistry {
+    games: RwLock<HashMap<u64, ChainGame>>,
+    /// First block whose events haven't been applied yet
+    next_block: AtomicU64,
+}
+
+impl GameRegistry {
+    pub async fn get(&self, game_id: u64) -> Option<ChainGame> {
+        self.games.read().await.get(&game_id).cloned()
+    }
+
+    /// First block whose events haven't been applied yet, every game started before it is known
+    pub fn next_block(&self) -> u64 {
+        self.next_block.load(Ordering::Acquire)
+    }
+
+    /// Records that the events of every block before `next_block` have been applied
+    pub fn processed(&self, next_block: u64) {
+        self.next_block.fetch_max(next_block, Ordering::AcqRel);
+    }
+
+    /// Block to follow the events from after a restart. Every game that is still unfinished
+    /// started in it or later, so none of them is missed.
+    pub async fn resume_block(&self) -> u64 {
+        let games = self.games.read().await;
+        games
+            .values()
+            .filter(|game| !game.finished())
+            .map(|game| game.start.block_number)
+            .fold(self.next_block(), u64::min)
+    }
+
+    /// Games that haven't been settled or reclaimed yet
+    pub async fn unfinished(&self) -> Vec<ChainGame> {
+        let games = self.games.read().await;
+        let mut unfinished =
+            games.values().filter(|game| !game.finished()).cloned().collect::<Vec<_>>();
+        unfinished.sort_by_key(|game| game.start.game_index);
+        unfinished
+    }
+
+    /// Records a started game, unless it is already known
+    pub async fn insert(&self, start: StartData) {
+        self.games.write().await.entry(start.game_index).or_insert_with(|| ChainGame::new(start));
+    }
+
+    /// Marks a game as finished, for games the contract finished without emitting `GameResult`
+    pub async fn finish(&self, game_id: u64) {
+        self.apply(ChainEvent::Update {
+            game_id,
+            update: GameUpdate::Finished,
+        })
+        .await;
+    }
+
+    /// Forgets a game once the dealer is done with it
+    pub async fn remove(&self, game_id: u64) {
+        self.games.write().await.remove(&game_id);
+    }
+
+    /// Forgets the finished games that aren't in `live`, nothing is left to do for them. Live
+    /// games are forgotten once they are retired.
+    pub async fn forget_finished(&self, live: &HashSet<u64>) {
+        self.games.write().await.retain(|game_id, game| !game.finished() || live.contains(game_id));
+    }
+
+    /// Applies an event to the game it belongs to. Updates of unknown games are ignored, they
+    /// belong to other dealers.
+    pub async fn apply(&self, event: ChainEvent) {
+        match event {
+            ChainEvent::GameStarted(start) => self.insert(start).await,
+            ChainEvent::Update {
+                game_id,
+                update,
+            } => {
+                if let Some(game) = self.games.write().await.get_mut(&game_id) {
+                    game.apply(update);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,243 @@
+//! Dealer seeds, generated from the OS RNG and stored encrypted on disk
+//!
+//! Every game captures the dealer's commitment at the time it is started, so a game has to be
+//! dealt and proven with the seed of that commitment even after the dealer moved on to a new one
+//! with `updateCommitment`. Seeds are therefore looked up by their commitment, and an old seed is
+//! only deleted once every game that captured it has been settled.
+//!
+//! Each seed is an Ethereum keystore file (scrypt and AES-128-CTR) named after its commitment.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use blackjack_core::commitment;
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+use crate::eth::Blockchain;
+use crate::registry::GameRegistry;
+use crate::sm::Games;
+use crate::store::GameStore;
+
+pub struct SeedManager {
+    dir: PathBuf,
+    password: String,
+    /// Seeds by their commitment
+    seeds: RwLock<HashMap<[u8; 32], [u8; 16]>>,
+}
+
+impl SeedManager {
+    /// Decrypts every seed stored in `dir`, creating it if needed
+    pub fn open(dir: impl Into<PathBuf>, password: &str) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+        let mut seeds = HashMap::new();
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            let Some(stored) = commitment_of(name) else {
+                continue;
+            };
+            seeds.insert(stored, load(&path, password, &stored)?);
+        }
+        Ok(Self {
+            dir,
+            password: password.to_string(),
+            seeds: RwLock::new(seeds),
+        })
+    }
+
+    /// Seed of a commitment, as captured by a game in `Game.dealerCommitment`. Seeds another
+    /// process stored in the same directory since opening are picked up as well.
+    pub fn get(&self, commitment: &[u8; 32]) -> Option<[u8; 16]> {
+        if let Some(seed) = self.seeds.read().unwrap().get(commitment) {
+            return Some(*seed);
+        }
+        let path = self.dir.join(file_name(commitment));
+        if !path.exists() {
+            return None;
+        }
+        match load(&path, &self.password, commitment) {
+            Ok(seed) => {
+                self.seeds.write().unwrap().insert(*commitment, seed);
+                Some(seed)
+            }
+            Err(err) => {
+                log::warn!("{:?}", err);
+                None
+            }
+        }
+    }
+
+    /// Commitments of every stored seed
+    pub fn commitments(&self) -> Vec<[u8; 32]> {
+        self.seeds.read().unwrap().keys().copied().collect()
+    }
+
+    /// Generates a seed and stores it, returning its commitment. The seed is on disk before the
+    /// commitment can end up on chain, so a crash can't lose the seed of a game.
+    pub fn generate(&self) -> Result<[u8; 32]> {
+        let mut seed = [0; 16];
+        OsRng.fill_bytes(&mut seed);
+        let commitment = commitment(&seed);
+        eth_keystore::encrypt_key(
+            &self.dir,
+            &mut OsRng,
+            seed,
+            &self.password,
+            Some(&file_name(&commitment)),
+        )?;
+        self.seeds.write().unwrap().insert(commitment, seed);
+        Ok(commitment)
+    }
+
+    /// Generates a seed and commits to it on chain for the games started from now on
+    pub async fn rotate(&self, eth: &Blockchain) -> Result<[u8; 32]> {
+        let commitment = self.generate()?;
+        eth.update_commitment(commitment).await?;
+        Ok(commitment)
+    }
+
+    /// Deletes every seed whose commitment isn't in `keep`
+    pub fn prune(&self, keep: &HashSet<[u8; 32]>) -> Result<Vec<[u8; 32]>> {
+        let mut seeds = self.seeds.write().unwrap();
+        let retired = seeds
+            .keys()
+            .filter(|commitment| !keep.contains(*commitment))
+            .copied()
+            .collect::<Vec<_>>();
+        for commitment in &retired {
+            match std::fs::remove_file(self.dir.join(file_name(commitment))) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+            seeds.remove(commitment);
+        }
+        Ok(retired)
+    }
+}
+
+/// Periodically commits to a fresh seed, then deletes the seeds no unsettled game and no future
+/// game can be dealt with. `registry` has to know every game of the dealer that isn't settled,
+/// i.e. `eth_task` has to follow the events from before the oldest of them.
+pub async fn seed_task(
+    eth: Arc<Blockchain>,
+    seeds: Arc<SeedManager>,
+    registry: Arc<GameRegistry>,
+    games: Arc<Games>,
+    store: Arc<dyn GameStore>,
+    interval: Duration,
+) {
+    // commitments rotated away from, with the block they were replaced by. Until `eth_task` is
+    // past that block, games started with them may be missing from `registry`.
+    let mut replaced = Vec::new();
+    loop {
+        tokio::time::sleep(interval).await;
+        match rotate(&eth, &seeds).await {
+            Ok((previous, block)) => replaced.push((previous, block)),
+            Err(err) => {
+                log::warn!("rotating the dealer seed failed: {:?}", err);
+                continue;
+            }
+        }
+        replaced.retain(|&(_, block)| registry.next_block() <= block);
+        if let Err(err) = prune(&eth, &seeds, &registry, &games, &store, &replaced).await {
+            log::warn!("pruning dealer seeds failed: {:?}", err);
+        }
+    }
+}
+
+/// Rotates the seed, returning the commitment it replaced and a block at or after the rotation
+async fn rotate(eth: &Blockchain, seeds: &SeedManager) -> Result<([u8; 32], u64)> {
+    let previous = eth.dealers(eth.dealer()).await?.commitment.0;
+    let commitment = seeds.rotate(eth).await?;
+    log::info!("rotated to commitment {}", hex::encode(commitment));
+    Ok((previous, eth.block_number().await?))
+}
+
+async fn prune(
+    eth: &Blockchain,
+    seeds: &SeedManager,
+    registry: &GameRegistry,
+    games: &Games,
+    store: &Arc<dyn GameStore>,
+    replaced: &[([u8; 32], u64)],
+) -> Result<()> {
+    // games are started with whatever commitment is on chain when they are mined
+    let mut keep = HashSet::from([eth.dealers(eth.dealer()).await?.commitment.0]);
+    keep.extend(replaced.iter().map(|(commitment, _)| *commitment));
+    for game in registry.unfinished().await {
+        keep.insert(game.start.dealer_commitment.0);
+    }
+    // the dealer's own records cover games the registry doesn't know about
+    let live = games.read().await.values().cloned().collect::<Vec<_>>();
+    for sm in live {
+        keep.insert(commitment(&sm.lock().await.dealer_seed));
+    }
+    let store = store.clone();
+    for game in tokio::task::spawn_blocking(move || store.load()).await?? {
+        keep.insert(game.dealer_commitment.0);
+    }
+    for commitment in seeds.prune(&keep)? {
+        log::info!("deleted the seed of commitment {}", hex::encode(commitment));
+    }
+    Ok(())
+}
+
+fn load(path: &Path, password: &str, stored: &[u8; 32]) -> Result<[u8; 16]> {
+    let seed: [u8; 16] = eth_keystore::decrypt_key(path, password)
+        .with_context(|| format!("decrypting {}", path.display()))?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("{} doesn't hold a seed", path.display()))?;
+    if commitment(&seed) != *stored {
+        anyhow::bail!("{} holds the seed of another commitment", path.display());
+    }
+    Ok(seed)
+}
+
+fn file_name(commitment: &[u8; 32]) -> String {
+    format!("{}.json", hex::encode(commitment))
+}
+
+fn commitment_of(file_name: &str) -> Option<[u8; 32]> {
+    hex::decode(Path::new(file_name).file_stem()?.to_str()?).ok()?.try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeds_survive_reopening() {
+        let dir = std::env::temp_dir().join(format!("seeds-{}", std::process::id()));
+        let seeds = SeedManager::open(&dir, "password").unwrap();
+        let first = seeds.generate().unwrap();
+        let second = seeds.generate().unwrap();
+        assert_ne!(first, second);
+        assert_eq!(commitment(&seeds.get(&first).unwrap()), first);
+
+        let reopened = SeedManager::open(&dir, "password").unwrap();
+        assert_eq!(reopened.get(&first), seeds.get(&first));
+        assert_eq!(reopened.get(&second), seeds.get(&second));
+        assert!(SeedManager::open(&dir, "wrong password").is_err());
+
+        // seeds generated by another process are found on disk
+        let third = seeds.generate().unwrap();
+        assert_eq!(reopened.get(&third), seeds.get(&third));
+
+        let mut retired = reopened.prune(&HashSet::from([second])).unwrap();
+        retired.sort();
+        let mut expected = vec![first, third];
+        expected.sort();
+        assert_eq!(retired, expected);
+        assert_eq!(reopened.get(&first), None);
+        let reopened = SeedManager::open(&dir, "password").unwrap();
+        assert_eq!(reopened.commitments(), vec![second]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,641 @@
+//! Settlement of finished games on chain with `proveGames`
+//!
+//! A player can reclaim 2.5x of the bets with `reclaimGame` once `gameStartBlock + timeoutBlocks`
+//! has passed, so games are settled in order of their deadlines, in batches small enough to be
+//! proven and mined before the first of them runs out.
+//!
+//! Games the player stopped playing are settled as not terminated: once `timeoutBlocks / 4` have
+//! passed the player's actions are requested with `requestPlayerActions`, and the game is proven
+//! with the actions they provide, or with the dealer's transcript after another
+//! `timeoutBlocks / 2`.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use alloy_primitives::{B256, U256};
+use alloy_sol_types::SolValue;
+use anyhow::{Context, Result};
+use blackjack_core::{actions_hash, commitment};
+
+use crate::archive::{ArchivedGame, GameArchive};
+use crate::eth::Blockchain;
+use crate::payout::Prepaid;
+use crate::r0::prove_inner;
+use crate::registry::{ChainGame, GameRegistry};
+use crate::sm::{DeAction, GameInput, Games, Output};
+use crate::store::GameStore;
+
+pub struct SettleConfig {
+    /// How often finished games are collected
+    pub interval: Duration,
+    /// Maximum number of games proven together
+    pub max_batch: usize,
+    /// Average block time of the chain
+    pub block_time: Duration,
+    /// Initial estimate of the time to prove and submit a batch, on top of `proving_per_game`
+    pub proving_base: Duration,
+    /// Initial estimate of the proving time every game adds to a batch
+    pub proving_per_game: Duration,
+    /// Blocks a batch has to land ahead of the earliest deadline in it
+    pub safety_blocks: u64,
+    /// Games closer than this to their deadline are logged
+    pub warn_blocks: u64,
+}
+
+/// Periodically proves the terminated games in `games` and settles them with `proveGames`.
+/// Settled games are moved from `games` to `archive`.
+pub async fn settle_task(
+    eth: Arc<Blockchain>,
+    games: Arc<Games>,
+    registry: Arc<GameRegistry>,
+    prepaid: Arc<Prepaid>,
+    store: Arc<dyn GameStore>,
+    archive: Arc<GameArchive>,
+    config: SettleConfig,
+) {
+    let mut settler = Settler::new(registry, prepaid, store, archive, &config);
+    loop {
+        tokio::time::sleep(config.interval).await;
+        if let Err(err) = settler.settle_round(&eth, &games, &config).await {
+            log::warn!("settlement round failed: {:?}", err);
+        }
+    }
+}
+
+/// Runs a single settlement round over `games`, for settling outside of `settle_task`
+pub async fn settle_once(
+    eth: &Blockchain,
+    games: &Games,
+    registry: Arc<GameRegistry>,
+    prepaid: Arc<Prepaid>,
+    store: Arc<dyn GameStore>,
+    archive: Arc<GameArchive>,
+    config: &SettleConfig,
+) -> Result<()> {
+    Settler::new(registry, prepaid, store, archive, config).settle_round(eth, games, config).await
+}
+
+struct Settler {
+    /// Number of failed attempts per game. Games that failed once are retried on their own so
+    /// that they can't hold back the rest of a batch.
+    failures: HashMap<u64, u32>,
+    /// `gameStartBlock` of the games seen so far, it never changes
+    start_blocks: HashMap<u64, u64>,
+    /// Abandoned games `requestPlayerActions` was sent for
+    requested: HashSet<u64>,
+    estimate: ProvingEstimate,
+    registry: Arc<GameRegistry>,
+    prepaid: Arc<Prepaid>,
+    store: Arc<dyn GameStore>,
+    archive: Arc<GameArchive>,
+}
+
+impl Settler {
+    fn new(
+        registry: Arc<GameRegistry>,
+        prepaid: Arc<Prepaid>,
+        store: Arc<dyn GameStore>,
+        archive: Arc<GameArchive>,
+        config: &SettleConfig,
+    ) -> Self {
+        Self {
+            failures: HashMap::new(),
+            start_blocks: HashMap::new(),
+            requested: HashSet::new(),
+            estimate: ProvingEstimate {
+                base: config.proving_base,
+                per_game: config.proving_per_game,
+            },
+            registry,
+            prepaid,
+            store,
+            archive,
+        }
+    }
+
+    async fn settle_round(
+        &mut self,
+        eth: &Blockchain,
+        games: &Games,
+        config: &SettleConfig,
+    ) -> Result<()> {
+        let pending = self.collect(eth, games, config).await?;
+        let now = eth.block_number().await?;
+        for batch in plan(pending, now, &self.estimate, config) {
+            let game_ids = batch.games.iter().map(|game| game.gameId).collect::<Vec<_>>();
+            let started = Instant::now();
+            match settle_batch(eth, batch).await {
+                Ok((tx_hash, payouts)) => {
+                    log::info!("settled games {:?} in {:?}", game_ids, tx_hash);
+                    self.estimate.observe(game_ids.len(), started.elapsed());
+                    for (game_id, payout) in game_ids.into_iter().zip(payouts) {
+                        let game_id = game_id.to::<u64>();
+                        self.registry.finish(game_id).await;
+                        self.retire(games, game_id, Some(B256::from(tx_hash.0))).await;
+                        // the contract only transfers the part of the payout that wasn't prepaid
+                        let prepaid = self.prepaid.settle(game_id).await;
+                        if prepaid > payout {
+                            log::warn!(
+                                "game {} was prepaid {}, more than its proven payout {}",
+                                game_id,
+                                prepaid,
+                                payout
+                            );
+                        } else if !prepaid.is_zero() {
+                            log::info!("game {} prepaid {} of {}", game_id, prepaid, payout);
+                        }
+                    }
+                }
+                Err(err) => {
+                    log::warn!("settling games {:?} failed: {:?}", game_ids, err);
+                    for game_id in game_ids {
+                        *self.failures.entry(game_id.to::<u64>()).or_default() += 1;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Transcripts and deadlines of the games that are ready to be settled. Warns about every
+    /// unsettled game close to its deadline, including ones still being played.
+    async fn collect(
+        &mut self,
+        eth: &Blockchain,
+        games: &Games,
+        config: &SettleConfig,
+    ) -> Result<Vec<Pending>> {
+        let timeout_blocks = eth.timeout_blocks().await?;
+        let now = eth.block_number().await?;
+
+        // copied and released before any RPC call, so a slow node never holds up the players.
+        // Every game is locked after the games lock is released, `/action` holds a game's lock
+        // across a disk write.
+        let live = games.read().await.iter().map(|(&id, sm)| (id, sm.clone())).collect::<Vec<_>>();
+        let live_ids = live.iter().map(|(id, _)| *id).collect::<HashSet<_>>();
+        let mut copies = Vec::new();
+        for (game_id, sm) in live {
+            let sm = sm.lock().await;
+            copies.push((game_id, sm.dealer_seed, sm.terminated(), sm.transcript()));
+        }
+
+        let mut pending = Vec::new();
+        let mut finished = Vec::new();
+        for (game_id, dealer_seed, terminated, transcript) in copies {
+            // settled before a restart, or reclaimed by the player
+            if self.registry.get(game_id).await.is_some_and(|game| game.finished()) {
+                finished.push(game_id);
+                continue;
+            }
+            let start_block = match self.start_blocks.get(&game_id) {
+                Some(&start_block) => start_block,
+                None => {
+                    let game = eth.games(game_id).await?;
+                    if game.finished {
+                        self.registry.finish(game_id).await;
+                        finished.push(game_id);
+                        continue;
+                    }
+                    self.start_blocks.insert(game_id, game.start_block);
+                    game.start_block
+                }
+            };
+            let deadline = start_block + timeout_blocks;
+            if now + config.warn_blocks >= deadline {
+                log::warn!(
+                    "game {} is {} blocks from its timeout (terminated: {})",
+                    game_id,
+                    deadline.saturating_sub(now),
+                    terminated
+                );
+            }
+            let input = if terminated {
+                transcript
+            } else {
+                match self.abandoned(eth, game_id, Some(transcript), timeout_blocks, now).await {
+                    Some(input) => input,
+                    None => continue,
+                }
+            };
+            pending.push(Pending {
+                dealer_seed,
+                input,
+                terminated,
+                deadline,
+                retry: self.failures.contains_key(&game_id),
+            });
+        }
+        // games started on chain that the player never dealt with `/start`. They can't be proven
+        // without the player's seed, but requesting their actions keeps the player from waiting
+        // out the timeout and reclaiming them.
+        let unfinished = self.registry.unfinished().await;
+        for game in &unfinished {
+            let game_id = game.start.game_index;
+            if live_ids.contains(&game_id) {
+                continue;
+            }
+            let deadline = game.start.block_number + timeout_blocks;
+            if now + config.warn_blocks >= deadline {
+                log::warn!(
+                    "game {} is {} blocks from its timeout and was never started with /start",
+                    game_id,
+                    deadline.saturating_sub(now)
+                );
+            }
+            self.abandoned(eth, game_id, None, timeout_blocks, now).await;
+        }
+        let unfinished =
+            unfinished.iter().map(|game| game.start.game_index).collect::<HashSet<_>>();
+        self.requested.retain(|game_id| live_ids.contains(game_id) || unfinished.contains(game_id));
+        self.registry.forget_finished(&live_ids).await;
+
+        for game_id in finished {
+            self.retire(games, game_id, None).await;
+        }
+        Ok(pending)
+    }
+
+    /// Moves a game that is finished on chain from the live games to the archive. A game that
+    /// can't be archived stays live, and is retried by the next round.
+    async fn retire(&mut self, games: &Games, game_id: u64, tx_hash: Option<B256>) {
+        let Some(sm) = games.read().await.get(&game_id).cloned() else {
+            return;
+        };
+        let snapshot = sm.lock().await.snapshot();
+        if let Err(err) = self.archive.insert(&ArchivedGame {
+            snapshot,
+            tx_hash,
+        }) {
+            log::warn!("archiving game {} failed: {:?}", game_id, err);
+            return;
+        }
+        if let Err(err) = self.store.settled(game_id) {
+            log::warn!("recording game {} as settled failed: {:?}", game_id, err);
+        }
+        games.write().await.remove(&game_id);
+        self.registry.remove(game_id).await;
+        self.failures.remove(&game_id);
+        self.start_blocks.remove(&game_id);
+        self.requested.remove(&game_id);
+    }
+
+    /// Transcript to prove a game that isn't terminated with, see `abandoned_step`. Sends
+    /// `requestPlayerActions` once it is due.
+    async fn abandoned(
+        &mut self,
+        eth: &Blockchain,
+        game_id: u64,
+        transcript: Option<GameInput>,
+        timeout_blocks: u64,
+        now: u64,
+    ) -> Option<GameInput> {
+        let game = self.registry.get(game_id).await?;
+        match abandoned_step(game, transcript, timeout_blocks, now) {
+            Abandoned::Wait => None,
+            Abandoned::Request => {
+                if self.requested.insert(game_id) {
+                    log::info!("game {} looks abandoned, requesting the player's actions", game_id);
+                    if let Err(err) = eth.request_player_actions(game_id).await {
+                        log::warn!("requesting actions of game {} failed: {:?}", game_id, err);
+                        self.requested.remove(&game_id);
+                    }
+                }
+                None
+            }
+            Abandoned::Prove(input) => Some(input),
+            Abandoned::Unprovable(reason) => {
+                log::error!("game {} can't be proven, {}", game_id, reason);
+                None
+            }
+        }
+    }
+}
+
+/// Next step for a game that isn't terminated
+enum Abandoned {
+    /// Nothing to do yet
+    Wait,
+    /// The player's actions are due to be requested with `requestPlayerActions`
+    Request,
+    /// The contract accepts a proof of the game as not terminated now
+    Prove(GameInput),
+    /// The contract would accept a proof the dealer can't make, for the given reason
+    Unprovable(&'static str),
+}
+
+/// Decides what to do about a game that isn't terminated. `transcript` holds the actions the
+/// dealer accepted, `None` for a game the player started on chain but never dealt with `/start`.
+/// After a quarter of the timeout the player's actions are requested, which also keeps the player
+/// from reclaiming the game until they provide them. The game is proven once they do, or once
+/// another half of the timeout passed without them.
+fn abandoned_step(
+    game: ChainGame,
+    transcript: Option<GameInput>,
+    timeout_blocks: u64,
+    now: u64,
+) -> Abandoned {
+    let Some(requested_block) = game.actions_requested_block else {
+        return if now > game.start.block_number + timeout_blocks / 4 {
+            Abandoned::Request
+        } else {
+            Abandoned::Wait
+        };
+    };
+    if game.actions_hash.is_none() && now <= requested_block + timeout_blocks / 2 {
+        return Abandoned::Wait;
+    }
+    let Some(transcript) = transcript else {
+        return Abandoned::Unprovable("the player never revealed their seed with /start");
+    };
+    match (game.actions_hash, game.provided_actions) {
+        // the guest has to report the hash of exactly the actions the player provided
+        (Some(_), Some(provided)) => Abandoned::Prove(with_provided_actions(transcript, provided)),
+        (Some(hash), None) if B256::from(actions_hash(&transcript.actions)) == hash => {
+            Abandoned::Prove(transcript)
+        }
+        (Some(_), None) => Abandoned::Unprovable("the provided actions are unknown"),
+        (None, _) => Abandoned::Prove(transcript),
+    }
+}
+
+/// Replaces the actions of a transcript with the ones the player provided on chain. Only actions
+/// the dealer accepted keep their signature, the guest reports the game as not terminated as soon
+/// as one is missing.
+fn with_provided_actions(transcript: GameInput, provided: Vec<DeAction>) -> GameInput {
+    let signatures = provided
+        .iter()
+        .enumerate()
+        .map(|(i, action)| match transcript.actions.get(i) {
+            Some(accepted) if accepted == action => transcript.signatures[i],
+            _ => [B256::ZERO; 2],
+        })
+        .collect();
+    GameInput {
+        actions: provided,
+        signatures,
+        ..transcript
+    }
+}
+
+/// Time it takes to prove and submit a batch, learned from the batches settled so far
+struct ProvingEstimate {
+    base: Duration,
+    per_game: Duration,
+}
+
+impl ProvingEstimate {
+    fn batch_time(&self, games: usize) -> Duration {
+        self.base + self.per_game * games as u32
+    }
+
+    fn blocks(&self, games: usize, block_time: Duration) -> u64 {
+        self.batch_time(games).as_secs().div_ceil(block_time.as_secs().max(1))
+    }
+
+    fn observe(&mut self, games: usize, elapsed: Duration) {
+        let per_game = elapsed.saturating_sub(self.base) / games as u32;
+        // moving average, so that a single slow or fast batch doesn't throw off the schedule
+        self.per_game = (self.per_game * 3 + per_game) / 4;
+    }
+}
+
+struct Pending {
+    dealer_seed: [u8; 16],
+    input: GameInput,
+    /// Terminated according to the state machine, abandoned games aren't
+    terminated: bool,
+    /// Block after which the player can reclaim the game
+    deadline: u64,
+    /// Settling the game failed before
+    retry: bool,
+}
+
+struct Batch {
+    dealer_seed: [u8; 16],
+    games: Vec<GameInput>,
+    terminated: Vec<bool>,
+}
+
+/// Splits the pending games into batches, most urgent first. Batches are settled one after
+/// another, so a batch only grows while it, and every batch before it, can still be settled
+/// `safety_blocks` ahead of its earliest deadline. A proof covers a single dealer commitment,
+/// so a change of dealer seed also starts a new batch.
+fn plan(
+    mut pending: Vec<Pending>,
+    now: u64,
+    estimate: &ProvingEstimate,
+    config: &SettleConfig,
+) -> Vec<Batch> {
+    pending.sort_by_key(|game| (game.deadline, game.input.gameId));
+
+    let mut batches = Vec::<Batch>::new();
+    // blocks taken by the batches before the open one
+    let mut elapsed = 0;
+    // earliest deadline of the open batch
+    let mut deadline = 0;
+    let mut open_is_retry = false;
+    for game in pending {
+        let fits = batches.last().is_some_and(|batch| {
+            let size = batch.games.len() + 1;
+            !open_is_retry
+                && !game.retry
+                && batch.dealer_seed == game.dealer_seed
+                && size <= config.max_batch
+                && now + elapsed + estimate.blocks(size, config.block_time) + config.safety_blocks
+                    <= deadline
+        });
+        if fits {
+            let batch = batches.last_mut().unwrap();
+            batch.games.push(game.input);
+            batch.terminated.push(game.terminated);
+            continue;
+        }
+
+        if let Some(batch) = batches.last() {
+            elapsed += estimate.blocks(batch.games.len(), config.block_time);
+        }
+        if now + elapsed + estimate.blocks(1, config.block_time) + config.safety_blocks
+            > game.deadline
+        {
+            log::error!(
+                "game {} can't be settled before its deadline at block {}",
+                game.input.gameId,
+                game.deadline
+            );
+        }
+        deadline = game.deadline;
+        open_is_retry = game.retry;
+        batches.push(Batch {
+            dealer_seed: game.dealer_seed,
+            games: vec![game.input],
+            terminated: vec![game.terminated],
+        });
+    }
+    batches
+}
+
+/// Proves a batch and submits it, returning the hash of the mined `proveGames` transaction and
+/// the proven payouts
+async fn settle_batch(eth: &Blockchain, batch: Batch) -> Result<(ethers::types::H256, Vec<U256>)> {
+    let (chain_id, contract) = (eth.chain_id(), eth.contract());
+    let dealer_seed = batch.dealer_seed;
+    // proving takes minutes, keep it off the async workers
+    let terminated = batch.terminated;
+    let games = batch.games;
+    let (seal, journal) =
+        tokio::task::spawn_blocking(move || prove_inner(games, dealer_seed, chain_id, contract))
+            .await??;
+    let output = Output::abi_decode(&journal, true).context("decode journal")?;
+    if output.dealer_commitment.0 != commitment(&dealer_seed) {
+        anyhow::bail!("journal commits to a different dealer seed");
+    }
+    for (i, &terminated) in terminated.iter().enumerate() {
+        if terminated && !output.terminated[i] {
+            anyhow::bail!("game {} didn't terminate in the guest", output.game_ids[i]);
+        }
+    }
+    let payouts = output.payouts.clone();
+    let tx_hash = eth.prove_games(&journal, seal).await?;
+    Ok((tx_hash, payouts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eth::StartData;
+    use alloy_primitives::Address;
+
+    fn config() -> SettleConfig {
+        SettleConfig {
+            interval: Duration::from_secs(60),
+            max_batch: 3,
+            block_time: Duration::from_secs(12),
+            proving_base: Duration::from_secs(120),
+            proving_per_game: Duration::from_secs(60),
+            safety_blocks: 10,
+            warn_blocks: 50,
+        }
+    }
+
+    fn pending(game_id: u64, deadline: u64) -> Pending {
+        Pending {
+            dealer_seed: [0; 16],
+            input: GameInput {
+                gameId: U256::from(game_id),
+                playerSeed: Default::default(),
+                pubkey: Default::default(),
+                initialHands: 1,
+                bets: vec![U256::from(1)],
+                actions: Vec::new(),
+                signatures: Vec::new(),
+            },
+            terminated: true,
+            deadline,
+            retry: false,
+        }
+    }
+
+    fn ids(batches: &[Batch]) -> Vec<Vec<u64>> {
+        batches
+            .iter()
+            .map(|batch| batch.games.iter().map(|game| game.gameId.to::<u64>()).collect())
+            .collect()
+    }
+
+    fn action(nonce: u8, inner: u8) -> DeAction {
+        DeAction {
+            nonce,
+            handId: 0,
+            inner,
+            my_cards: vec![2, 9],
+            dealer_cards: vec![10],
+        }
+    }
+
+    #[test]
+    fn test_provided_actions_keep_accepted_signatures() {
+        let signature = [B256::repeat_byte(1), B256::repeat_byte(2)];
+        let mut transcript = pending(0, 0).input;
+        transcript.actions = vec![action(0, 1)];
+        transcript.signatures = vec![signature];
+
+        let input = with_provided_actions(transcript, vec![action(0, 1), action(1, 0)]);
+        assert_eq!(input.actions, vec![action(0, 1), action(1, 0)]);
+        assert_eq!(input.signatures, vec![signature, [B256::ZERO; 2]]);
+
+        // a different first action loses the signature
+        let mut transcript = pending(0, 0).input;
+        transcript.actions = vec![action(0, 1)];
+        transcript.signatures = vec![signature];
+        let input = with_provided_actions(transcript, vec![action(0, 2)]);
+        assert_eq!(input.signatures, vec![[B256::ZERO; 2]]);
+    }
+
+    fn chain_game(start_block: u64) -> ChainGame {
+        let player = k256::ecdsa::SigningKey::from_slice(&[7; 32]).unwrap();
+        ChainGame::new(StartData {
+            player: Address::ZERO,
+            block_number: start_block,
+            bets: vec![U256::from(1)],
+            dealer_commitment: B256::ZERO,
+            player_commitment: vec![0; 32],
+            player_pubkey: *player.verifying_key(),
+            game_index: 0,
+        })
+    }
+
+    #[test]
+    fn test_unstarted_games_get_their_actions_requested() {
+        // started on chain at block 1000 with a timeout of 400 blocks, never dealt with `/start`
+        let mut game = chain_game(1000);
+        let step =
+            |game: &ChainGame, transcript, now| abandoned_step(game.clone(), transcript, 400, now);
+        assert!(matches!(step(&game, None, 1100), Abandoned::Wait));
+        assert!(matches!(step(&game, None, 1101), Abandoned::Request));
+
+        game.actions_requested_block = Some(1101);
+        assert!(matches!(step(&game, None, 1301), Abandoned::Wait));
+        assert!(matches!(step(&game, None, 1302), Abandoned::Unprovable(_)));
+        // the same path proves a game the dealer has a transcript of
+        let transcript = pending(0, 0).input;
+        assert!(matches!(step(&game, Some(transcript.clone()), 1301), Abandoned::Wait));
+        assert!(matches!(step(&game, Some(transcript), 1302), Abandoned::Prove(_)));
+    }
+
+    #[test]
+    fn test_plan_orders_by_deadline_and_caps_batches() {
+        let config = config();
+        let estimate = ProvingEstimate {
+            base: config.proving_base,
+            per_game: config.proving_per_game,
+        };
+        let games = (0..5).map(|id| pending(id, 10_000 - id)).collect();
+        assert_eq!(ids(&plan(games, 0, &estimate, &config)), vec![vec![4, 3, 2], vec![1, 0]]);
+    }
+
+    #[test]
+    fn test_plan_shrinks_batches_near_deadline() {
+        let config = config();
+        let estimate = ProvingEstimate {
+            base: config.proving_base,
+            per_game: config.proving_per_game,
+        };
+        // a batch of two takes 20 blocks, which leaves game 0 less than the safety margin
+        let games = vec![pending(0, 1029), pending(1, 1100), pending(2, 1100)];
+        assert_eq!(ids(&plan(games, 1000, &estimate, &config)), vec![vec![0], vec![1, 2]]);
+    }
+
+    #[test]
+    fn test_plan_retries_failed_games_alone() {
+        let config = config();
+        let estimate = ProvingEstimate {
+            base: config.proving_base,
+            per_game: config.proving_per_game,
+        };
+        let mut failed = pending(1, 10_000);
+        failed.retry = true;
+        let games = vec![pending(0, 10_000), failed, pending(2, 10_000)];
+        assert_eq!(ids(&plan(games, 0, &estimate, &config)), vec![vec![0], vec![1], vec![2]]);
+    }
+}
@@ -1,36 +1,44 @@
 //! State machine for blackjack game
 
-use alloy_primitives::U256;
-use alloy_sol_types::sol;
-use alloy_sol_types::SolValue;
-use k256::ecdsa::signature::Verifier;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use alloy_primitives::{Address, Bytes, FixedBytes, B256, U256};
+use alloy_sol_types::{Eip712Domain, SolValue};
+use blackjack_core::{
+    action_domain, action_signing_hash, commitment, game_seed, Action, Blackjack, BlackjackState,
+};
+use k256::ecdsa::signature::hazmat::PrehashVerifier;
 use k256::ecdsa::VerifyingKey;
-use rand::Rng;
-use rand::SeedableRng;
-use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, RwLock};
 
+pub use blackjack_core::{ActionType, DeAction, GameInput, Input, Output};
+
+/// Version of the `Snapshot` layout and of the rules it was taken under. Bump it whenever either
+/// changes, older snapshots are then migrated by replaying their actions.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Live games by game id, shared by the web server and the settlement task
+pub type Games = RwLock<HashMap<u64, Arc<Mutex<BlackjackStateMachine>>>>;
+
+#[derive(Clone)]
 pub struct BlackjackStateMachine {
     pub dealer_seed: [u8; 16],
     player_seed: [u8; 16],
-    pub dealer_hand: Vec<u8>,
-    pub player_hands: Vec<Vec<u8>>,
-    doubles: Vec<u8>,
-    splits: Vec<u8>,
+    game_id: u64,
+    /// Domain the player's signatures are bound to
+    domain: Eip712Domain,
+    game: Blackjack,
 
     initial_bets: Vec<U256>,
-    bets: Vec<U256>,
-    // zeroed out in the beginning
-    winnings: Vec<U256>,
-    pub hands_active: Vec<bool>,
 
     player_pubkey: VerifyingKey,
 
-    rng: ChaCha8Rng,
-
-    hand_id: usize,
-
     actions: Vec<DeAction>,
     signatures: Vec<[u8; 64]>,
+    /// Transactions that paid for doubles and splits, so that one can't pay for two
+    payments: Vec<B256>,
 }
 
 impl BlackjackStateMachine {
@@ -39,318 +47,676 @@ impl BlackjackStateMachine {
         player_seed: [u8; 16],
         player_pubkey: VerifyingKey,
         bets: Vec<U256>,
+        chain_id: u64,
+        contract: Address,
+        game_id: u64,
     ) -> Self {
-        let bets_len = bets.len();
-        let mut s = Self {
+        Self {
             dealer_seed,
             player_seed,
-            dealer_hand: Vec::new(),
-            player_hands: std::iter::repeat(Vec::new()).take(bets_len).collect(),
-            doubles: Vec::new(),
-            splits: Vec::new(),
-
-            initial_bets: bets.clone(),
-            bets,
-            winnings: std::iter::repeat(U256::ZERO).take(bets_len).collect(),
-            hands_active: std::iter::repeat(true).take(bets_len).collect(),
-
+            game_id,
+            domain: action_domain(U256::from(chain_id), contract, U256::from(game_id)),
+            game: Blackjack::new(game_seed(dealer_seed, player_seed), bets.clone()),
+            initial_bets: bets,
             player_pubkey,
-            rng: ChaCha8Rng::from_seed(
-                dealer_seed
-                    .into_iter()
-                    .chain(player_seed)
-                    .collect::<Vec<_>>()
-                    .as_slice()
-                    .try_into()
-                    .unwrap(),
-            ),
-            hand_id: 0,
             actions: Vec::new(),
             signatures: Vec::new(),
-        };
+            payments: Vec::new(),
+        }
+    }
 
-        s.dealer_hand.push(get_card(&mut s.rng));
-        s.dealer_hand.push(get_card(&mut s.rng));
-        s.player_hands.iter_mut().for_each(|hand| {
-            hand.push(get_card(&mut s.rng));
-            hand.push(get_card(&mut s.rng));
-        });
+    /// Rebuilds a game from its transcript, verifying every signature and replaying every action
+    /// like `try_input` did when they were accepted. The payments of doubles and splits aren't
+    /// part of the transcript, so they can't be checked for reuse afterwards.
+    pub fn from_transcript(
+        dealer_seed: [u8; 16],
+        input: &GameInput,
+        chain_id: u64,
+        contract: Address,
+    ) -> Result<Self, TranscriptError> {
+        let game_id = u64::try_from(input.gameId).map_err(|_| TranscriptError::InvalidGameId)?;
+        let player_pubkey = VerifyingKey::from_sec1_bytes(&input.pubkey)
+            .map_err(|_| TranscriptError::InvalidPubkey)?;
+        if input.initialHands as usize != input.bets.len()
+            || input.actions.len() != input.signatures.len()
+        {
+            return Err(TranscriptError::InvalidLength);
+        }
 
-        s.player_hands.iter().enumerate().for_each(|(id, hand)| {
-            if is_blackjack(hand) {
-                s.hands_active[id] = false;
-            }
-        });
-
-        if is_blackjack(&s.dealer_hand) {
-            s.hands_active.iter_mut().enumerate().for_each(|(id, active)| {
-                if !*active {
-                    s.winnings[id] = s.bets[id];
-                }
-                *active = false
-            });
+        let mut sm = Self::new(
+            dealer_seed,
+            input.playerSeed.0,
+            player_pubkey,
+            input.bets.clone(),
+            chain_id,
+            contract,
+            game_id,
+        );
+        for (i, (action, [r, s])) in input.actions.iter().zip(&input.signatures).enumerate() {
+            let signature = [r.as_slice(), s.as_slice()].concat();
+            sm.try_input(action.clone(), &signature)
+                .map_err(|err| TranscriptError::Action(i, err))?;
         }
+        Ok(sm)
+    }
+
+    /// Captures the whole state of the game, down to the position in the deck. The dealer seed
+    /// is left out, only its commitment is kept.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            game_id: self.game_id,
+            dealer_commitment: commitment(&self.dealer_seed).into(),
+            player_seed: self.player_seed.into(),
+            player_pubkey: self.player_pubkey.to_encoded_point(false).as_bytes().to_vec().into(),
+            initial_bets: self.initial_bets.clone(),
+            actions: self.actions.iter().map(|action| action.abi_encode().into()).collect(),
+            signatures: self.signatures.iter().map(|&signature| signature.into()).collect(),
+            payments: self.payments.clone(),
+            game: self.game.state().into(),
+        }
+    }
 
-        s
+    /// Resumes a game from `snapshot` by replaying its actions like `from_transcript`. The game
+    /// state of a current snapshot has to match the replayed one, older snapshots are replayed
+    /// under the current rules.
+    pub fn from_snapshot(
+        dealer_seed: [u8; 16],
+        snapshot: Snapshot,
+        chain_id: u64,
+        contract: Address,
+    ) -> Result<Self, SnapshotError> {
+        if snapshot.version > SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(snapshot.version));
+        }
+        if commitment(&dealer_seed) != snapshot.dealer_commitment.0 {
+            return Err(SnapshotError::WrongDealerSeed);
+        }
+        let actions = snapshot
+            .actions
+            .iter()
+            .map(|action| DeAction::abi_decode(action, true))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| SnapshotError::InvalidState)?;
+
+        let input = GameInput {
+            gameId: U256::from(snapshot.game_id),
+            playerSeed: snapshot.player_seed,
+            pubkey: snapshot.player_pubkey,
+            initialHands: snapshot.initial_bets.len() as u8,
+            bets: snapshot.initial_bets,
+            actions,
+            signatures: snapshot
+                .signatures
+                .iter()
+                .map(|s| [s[0..32].try_into().unwrap(), s[32..].try_into().unwrap()])
+                .collect(),
+        };
+        let mut sm = Self::from_transcript(dealer_seed, &input, chain_id, contract)?;
+        if snapshot.version == SNAPSHOT_VERSION && GameState::from(sm.game.state()) != snapshot.game
+        {
+            return Err(SnapshotError::InvalidState);
+        }
+        sm.payments = snapshot.payments;
+        Ok(sm)
     }
 
-    pub fn try_input(&mut self, action: DeAction, signature: &[u8]) -> Result<(), anyhow::Error> {
-        let signature = k256::ecdsa::Signature::from_slice(&signature)?;
-        let msg = action.abi_encode();
-        self.player_pubkey.verify(&msg, &signature)?;
+    pub fn try_input(&mut self, action: DeAction, signature: &[u8]) -> Result<(), ActionError> {
+        let signature = self.verify_signature(&action, signature)?;
+        self.check_nonce(action.nonce)?;
 
-        self.try_action(Action::from(&action))?;
+        self.game.act(&Action::try_from(&action)?)?;
 
         self.actions.push(action);
         self.signatures.push(signature.to_bytes().as_slice().try_into().unwrap());
         Ok(())
     }
 
-    fn try_action(
+    /// Same as `try_input`, for a `Double` or `Split` paid for by the transaction `payment`
+    pub fn try_paid_input(
         &mut self,
-        Action {
-            hand_id,
-            inner,
-            my_cards,
-            dealer_cards,
-        }: Action,
-    ) -> Result<(), anyhow::Error> {
-        if self.terminated() {
-            anyhow::bail!("Game is terminated");
-        }
-        while !self.hands_active[self.hand_id] {
-            self.hand_id += 1;
-        }
-        if self.hand_id >= self.bets.len() {
-            anyhow::bail!("Game is terminated");
+        action: DeAction,
+        signature: &[u8],
+        payment: B256,
+    ) -> Result<(), ActionError> {
+        if self.payments.contains(&payment) {
+            return Err(ActionError::PaymentReused);
         }
-        if hand_id != self.hand_id as u8 {
-            anyhow::bail!("Invalid hand_id");
-        }
-        if my_cards != self.player_hands[hand_id as usize] {
-            anyhow::bail!("Invalid my_cards");
+        self.try_input(action, signature)?;
+        self.payments.push(payment);
+        Ok(())
+    }
+
+    /// Checks that the player signed `action` and that it is the next one for the hand being
+    /// played, without playing it. Authenticates a request before it costs an RPC call.
+    pub fn check_signed(&self, action: &DeAction, signature: &[u8]) -> Result<(), ActionError> {
+        self.verify_signature(action, signature)?;
+        self.check_nonce(action.nonce)?;
+        match self.current_hand() {
+            Some(hand) if hand == action.handId => Ok(()),
+            Some(_) => Err(ActionError::InvalidHand),
+            None => Err(ActionError::GameTerminated),
         }
-        if dealer_cards != self.dealer_hand {
-            anyhow::bail!("Invalid dealer_cards");
+    }
+
+    fn verify_signature(
+        &self,
+        action: &DeAction,
+        signature: &[u8],
+    ) -> Result<k256::ecdsa::Signature, ActionError> {
+        let signature = k256::ecdsa::Signature::from_slice(signature)
+            .map_err(|_| ActionError::InvalidSignature)?;
+        let msg = action_signing_hash(action, &self.domain);
+        self.player_pubkey
+            .verify_prehash(msg.as_slice(), &signature)
+            .map_err(|_| ActionError::InvalidSignature)?;
+        Ok(signature)
+    }
+
+    /// Fails unless `nonce` is the one the next action has to carry
+    pub fn check_nonce(&self, nonce: u8) -> Result<(), ActionError> {
+        // the guest requires `nonce == index`, a gap would fail the proof of the whole batch
+        let expected = self.next_nonce();
+        if nonce < expected {
+            return Err(ActionError::DuplicateNonce(expected));
         }
-        match inner {
-            ActionType::Hit => {
-                if self.sum(hand_id) > 21 {
-                    anyhow::bail!("Cannot hit");
-                }
-                self.player_hands[hand_id as usize].push(get_card(&mut self.rng));
-                if self.sum(hand_id) > 21 {
-                    self.hands_active[hand_id as usize] = false;
-                    self.hand_id += 1;
-                }
-            }
-            ActionType::Stand => {
-                self.hands_active[hand_id as usize] = false;
-                self.hand_id += 1;
-            }
-            ActionType::Double => {
-                if self.player_hands[hand_id as usize].len() != 2 {
-                    anyhow::bail!("Cannot double");
-                }
-                self.player_hands[hand_id as usize].push(get_card(&mut self.rng));
-                self.hands_active[hand_id as usize] = false;
-                self.bets[hand_id as usize] =
-                    self.bets[hand_id as usize].checked_mul(U256::from(2)).unwrap();
-                self.doubles.push(hand_id);
-                self.hand_id += 1;
-            }
-            ActionType::Split => {
-                if self.player_hands[hand_id as usize].len() != 2 {
-                    anyhow::bail!("Cannot split");
-                }
-                if self.player_hands.len() == 4 {
-                    anyhow::bail!("Cannot split");
-                }
-                if self.player_hands[hand_id as usize][0] != self.player_hands[hand_id as usize][1]
-                {
-                    anyhow::bail!("Cannot split");
-                }
-                self.player_hands
-                    .insert(hand_id as usize + 1, vec![self.player_hands[hand_id as usize][1]]);
-                self.bets.insert(hand_id as usize + 1, self.bets[hand_id as usize]);
-                self.player_hands[hand_id as usize].pop();
-                self.player_hands[hand_id as usize].push(get_card(&mut self.rng));
-                self.splits.push(hand_id);
-            }
+        if nonce > expected {
+            return Err(ActionError::InvalidNonce(expected));
         }
+        Ok(())
+    }
 
-        if self.terminated() {
-            while self.sum_max_dealer() < 17 {
-                self.dealer_hand.push(get_card(&mut self.rng));
-            }
-            let dealer_sum = self.sum_max_dealer();
-            self.player_hands.clone().iter().enumerate().for_each(|(id, hand)| {
-                let hand_sum = self.sum_max(id as u8);
-                if is_blackjack(hand) {
-                    self.winnings[id] = self.bets[id]
-                        .checked_mul(U256::from(5))
-                        .unwrap()
-                        .checked_div(U256::from(2))
-                        .unwrap();
-                } else if hand_sum > 21 {
-                    self.winnings[id] = U256::ZERO;
-                } else if dealer_sum > 21 || hand_sum > dealer_sum {
-                    self.winnings[id] = self.bets[id].checked_mul(U256::from(2)).unwrap();
-                } else if hand_sum == dealer_sum {
-                    self.winnings[id] = self.bets[id];
-                } else {
-                    self.winnings[id] = U256::ZERO;
-                }
-            });
-        }
+    /// Nonce the next action has to carry
+    pub fn next_nonce(&self) -> u8 {
+        self.actions.len() as u8
+    }
 
-        Ok(())
+    /// Hand the next action has to be for, `None` once the game is terminated
+    pub fn current_hand(&self) -> Option<u8> {
+        self.hands_active().iter().position(|&active| active).map(|hand| hand as u8)
     }
 
-    fn sum(&self, hand_id: u8) -> u8 {
-        self.player_hands[hand_id as usize].iter().sum()
+    pub fn player_pubkey(&self) -> &VerifyingKey {
+        &self.player_pubkey
     }
 
-    fn sum_max(&self, hand_id: u8) -> u8 {
-        let mut sum = self.sum(hand_id);
-        if self.player_hands[hand_id as usize].iter().any(|&card| card == 1) && sum + 10 <= 21 {
-            sum += 10;
-        }
-        sum
+    pub fn dealer_hand(&self) -> &[u8] {
+        self.game.dealer_hand()
     }
 
-    fn sum_max_dealer(&self) -> u8 {
-        let mut sum = self.dealer_hand.iter().sum();
-        if self.dealer_hand.iter().any(|&card| card == 1) && sum + 10 <= 21 {
-            sum += 10;
-        }
-        sum
+    /// Dealer's cards that can be shown to the player: the upcard until the round is resolved
+    pub fn visible_dealer_hand(&self) -> &[u8] {
+        self.game.visible_dealer_hand()
+    }
+
+    pub fn player_hands(&self) -> &[Vec<u8>] {
+        self.game.player_hands()
+    }
+
+    pub fn hands_active(&self) -> &[bool] {
+        self.game.hands_active()
+    }
+
+    /// Current bet of every hand, including doubles and splits
+    pub fn bets(&self) -> &[U256] {
+        self.game.bets()
+    }
+
+    /// Hands that were doubled, in the order of the actions
+    pub fn doubles(&self) -> &[u8] {
+        self.game.doubles()
+    }
+
+    /// Hands that were split, in the order of the actions
+    pub fn splits(&self) -> &[u8] {
+        self.game.splits()
+    }
+
+    /// Total payout of the game, including the returned bets. Zero while hands are still active.
+    pub fn winnings(&self) -> U256 {
+        self.game.payout()
     }
 
     pub fn terminated(&self) -> bool {
-        self.hands_active.iter().all(|&active| !active)
-    }
-
-    pub fn extract(self) -> Option<GameInput> {
-        if self.terminated() {
-            Some(GameInput {
-                playerSeed: self.player_seed.into(),
-                pubkey: self.player_pubkey.to_encoded_point(false).as_bytes().to_vec().into(),
-                initialHands: self.initial_bets.len() as u8,
-                bets: self.initial_bets,
-                actions: self.actions,
-                signatures: self
-                    .signatures
-                    .into_iter()
-                    .map(|s| [s[0..32].try_into().unwrap(), s[32..].try_into().unwrap()])
-                    .collect(),
-            })
-        } else {
-            None
+        self.game.terminated()
+    }
+
+    /// Transcript of the game for the guest, once it is terminated
+    pub fn extract(&self) -> Option<GameInput> {
+        self.terminated().then(|| self.transcript())
+    }
+
+    /// Transcript of the actions accepted so far, for games the player abandoned
+    pub fn transcript(&self) -> GameInput {
+        GameInput {
+            gameId: U256::from(self.game_id),
+            playerSeed: self.player_seed.into(),
+            pubkey: self.player_pubkey.to_encoded_point(false).as_bytes().to_vec().into(),
+            initialHands: self.initial_bets.len() as u8,
+            bets: self.initial_bets.clone(),
+            actions: self.actions.clone(),
+            signatures: self
+                .signatures
+                .iter()
+                .map(|s| [s[0..32].try_into().unwrap(), s[32..].try_into().unwrap()])
+                .collect(),
         }
     }
 }
 
-#[derive(serde::Deserialize)]
-pub struct Action {
-    pub hand_id: u8,
-    pub inner: ActionType,
-    pub my_cards: Vec<u8>,
-    pub dealer_cards: Vec<u8>,
+/// Serializable state of a game, see `BlackjackStateMachine::snapshot`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// `SNAPSHOT_VERSION` at the time the snapshot was taken
+    pub version: u32,
+    pub game_id: u64,
+    pub dealer_commitment: B256,
+    pub player_seed: FixedBytes<16>,
+    /// SEC1 encoded public key of the player
+    pub player_pubkey: Bytes,
+    pub initial_bets: Vec<U256>,
+    /// ABI encoded `DeAction`s
+    pub actions: Vec<Bytes>,
+    pub signatures: Vec<FixedBytes<64>>,
+    pub payments: Vec<B256>,
+    /// Has to match the replayed actions for the current version, ignored for older ones
+    pub game: GameState,
 }
 
-#[derive(PartialEq, Debug, serde::Deserialize)]
-pub enum ActionType {
-    Hit,
-    Stand,
-    Double,
-    Split,
+/// Serializable mirror of `blackjack_core::BlackjackState`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GameState {
+    /// Position of the deck's RNG, in 32-bit words
+    pub word_pos: u128,
+    pub dealer_hand: Vec<u8>,
+    pub player_hands: Vec<Vec<u8>>,
+    pub hands_active: Vec<bool>,
+    pub bets: Vec<U256>,
+    pub doubles: Vec<u8>,
+    pub splits: Vec<u8>,
+    pub winnings: Vec<U256>,
+    pub hand_id: usize,
 }
 
-impl From<u8> for ActionType {
-    fn from(value: u8) -> Self {
-        match value {
-            0 => ActionType::Hit,
-            1 => ActionType::Stand,
-            2 => ActionType::Double,
-            3 => ActionType::Split,
-            _ => panic!("Invalid action type"),
+impl From<BlackjackState> for GameState {
+    fn from(state: BlackjackState) -> Self {
+        Self {
+            word_pos: state.word_pos,
+            dealer_hand: state.dealer_hand,
+            player_hands: state.player_hands,
+            hands_active: state.hands_active,
+            bets: state.bets,
+            doubles: state.doubles,
+            splits: state.splits,
+            winnings: state.winnings,
+            hand_id: state.hand_id,
         }
     }
 }
 
-impl From<ActionType> for u8 {
-    fn from(value: ActionType) -> Self {
+impl From<GameState> for BlackjackState {
+    fn from(state: GameState) -> Self {
+        Self {
+            word_pos: state.word_pos,
+            dealer_hand: state.dealer_hand,
+            player_hands: state.player_hands,
+            hands_active: state.hands_active,
+            bets: state.bets,
+            doubles: state.doubles,
+            splits: state.splits,
+            winnings: state.winnings,
+            hand_id: state.hand_id,
+        }
+    }
+}
+
+/// Reasons an action can be rejected by the state machine
+#[derive(PartialEq, Debug)]
+pub enum ActionError {
+    /// Signature is malformed or doesn't match the player's public key
+    InvalidSignature,
+    /// Nonce was already used by an accepted action. Carries the expected nonce.
+    DuplicateNonce(u8),
+    /// Nonce skips ahead of the next expected one. Carries the expected nonce.
+    InvalidNonce(u8),
+    /// All hands are already resolved
+    GameTerminated,
+    /// Action is for a hand other than the one currently being played
+    InvalidHand,
+    /// Cards in the action don't match the current state of the table
+    InvalidCards,
+    /// Action is not allowed for the hand (e.g. splitting non-pairs)
+    InvalidAction,
+    /// Payment transaction was already used for another action
+    PaymentReused,
+}
+
+impl From<blackjack_core::Error> for ActionError {
+    fn from(value: blackjack_core::Error) -> Self {
         match value {
-            ActionType::Hit => 0,
-            ActionType::Stand => 1,
-            ActionType::Double => 2,
-            ActionType::Split => 3,
+            blackjack_core::Error::GameTerminated => ActionError::GameTerminated,
+            blackjack_core::Error::UnexpectedHand => ActionError::InvalidHand,
+            blackjack_core::Error::InvalidCards => ActionError::InvalidCards,
+            blackjack_core::Error::InvalidAction => ActionError::InvalidAction,
         }
     }
 }
 
-impl From<&DeAction> for Action {
-    fn from(v: &DeAction) -> Action {
-        Action {
-            hand_id: v.handId,
-            inner: v.inner.into(),
-            my_cards: v.my_cards.clone(),
-            dealer_cards: v.dealer_cards.clone(),
+impl std::fmt::Display for ActionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ActionError::InvalidSignature => write!(f, "invalid signature"),
+            ActionError::DuplicateNonce(expected) => {
+                write!(f, "nonce already used, expected {}", expected)
+            }
+            ActionError::InvalidNonce(expected) => {
+                write!(f, "nonce out of order, expected {}", expected)
+            }
+            ActionError::GameTerminated => write!(f, "game is terminated"),
+            ActionError::InvalidHand => write!(f, "invalid hand_id"),
+            ActionError::InvalidCards => write!(f, "cards don't match the game state"),
+            ActionError::InvalidAction => write!(f, "action not allowed"),
+            ActionError::PaymentReused => write!(f, "payment already used for another action"),
         }
     }
 }
 
-fn get_card(rng: &mut ChaCha8Rng) -> u8 {
-    let card: u8 = rng.gen::<u8>() % 13 + 1;
-    if card > 10 {
-        10
-    } else {
-        card
+impl std::error::Error for ActionError {}
+
+/// Reasons a transcript can't be replayed by `BlackjackStateMachine::from_transcript`
+#[derive(PartialEq, Debug)]
+pub enum TranscriptError {
+    /// Game id doesn't fit the ids the contract hands out
+    InvalidGameId,
+    /// Player's public key isn't a SEC1 encoded secp256k1 key
+    InvalidPubkey,
+    /// `initialHands` doesn't match the bets, or the signatures don't match the actions
+    InvalidLength,
+    /// Action at the index was rejected
+    Action(usize, ActionError),
+}
+
+impl std::fmt::Display for TranscriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TranscriptError::InvalidGameId => write!(f, "invalid game id"),
+            TranscriptError::InvalidPubkey => write!(f, "invalid player public key"),
+            TranscriptError::InvalidLength => write!(f, "transcript lengths don't match"),
+            TranscriptError::Action(i, err) => write!(f, "action {}: {}", i, err),
+        }
     }
 }
 
-fn is_blackjack(hand: &[u8]) -> bool {
-    hand.len() == 2 && hand.iter().any(|&card| card == 1) && hand.iter().any(|&card| card == 10)
+impl std::error::Error for TranscriptError {}
+
+/// Reasons a snapshot can't be resumed by `BlackjackStateMachine::from_snapshot`
+#[derive(PartialEq, Debug)]
+pub enum SnapshotError {
+    /// Snapshot was taken by a newer version. Carries its version.
+    UnsupportedVersion(u32),
+    /// Dealer seed doesn't match the commitment the game was dealt with
+    WrongDealerSeed,
+    /// Game state or actions are inconsistent
+    InvalidState,
+    /// Actions of the snapshot can't be replayed
+    Transcript(TranscriptError),
+}
+
+impl From<TranscriptError> for SnapshotError {
+    fn from(value: TranscriptError) -> Self {
+        SnapshotError::Transcript(value)
+    }
 }
 
-sol!(
-    struct Input {
-        bytes16 dealerSeed;
-        GameInput[] games;
-    }
-);
-
-sol!(
-    struct GameInput {
-        bytes16 playerSeed;
-        bytes pubkey;
-        uint8 initialHands;
-        uint256[] bets;
-        DeAction[] actions;
-        bytes32[2][] signatures;
-    }
-);
-
-sol!(
-    struct DeAction {
-        uint8 nonce;
-        uint8 handId;
-        uint8 inner;
-        uint8[] my_cards;
-        uint8[] dealer_cards;
-    }
-);
-
-sol!(
-    struct Output {
-        bytes32 dealer_commitment;
-        bytes32[] player_commitments;
-        bytes[] player_pubkeys;
-        uint256[] payouts;
-        uint8[][] double_hands;
-        uint8[][] split_hands;
-        bytes32[] action_hash;
-        bool[] terminated;
-    }
-);
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version {}", version)
+            }
+            SnapshotError::WrongDealerSeed => write!(f, "dealer seed doesn't match the commitment"),
+            SnapshotError::InvalidState => write!(f, "inconsistent game state"),
+            SnapshotError::Transcript(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k256::ecdsa::signature::hazmat::PrehashSigner;
+    use k256::ecdsa::{Signature, SigningKey};
+
+    const CHAIN_ID: u64 = 11155111;
+    const CONTRACT: Address = Address::repeat_byte(0x42);
+
+    fn dealer_seed() -> [u8; 16] {
+        let mut seed = [0u8; 16];
+        seed[..11].copy_from_slice(b"dealerSeed2");
+        seed
+    }
+
+    fn player_seed() -> [u8; 16] {
+        let mut seed = [0u8; 16];
+        seed[..10].copy_from_slice(b"playerSeed");
+        seed
+    }
+
+    fn sign(sk: &SigningKey, action: &DeAction) -> Vec<u8> {
+        let domain = action_domain(U256::from(CHAIN_ID), CONTRACT, U256::ZERO);
+        let signature: Signature =
+            sk.sign_prehash(action_signing_hash(action, &domain).as_slice()).unwrap();
+        signature.to_bytes().to_vec()
+    }
+
+    fn hit(sm: &BlackjackStateMachine, nonce: u8) -> DeAction {
+        act(sm, nonce, ActionType::Hit)
+    }
+
+    fn act(sm: &BlackjackStateMachine, nonce: u8, inner: ActionType) -> DeAction {
+        DeAction {
+            nonce,
+            handId: 0,
+            inner: inner.into(),
+            my_cards: sm.player_hands()[0].clone(),
+            dealer_cards: sm.visible_dealer_hand().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_nonces_are_sequential() {
+        let sk = SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let mut sm = BlackjackStateMachine::new(
+            dealer_seed(),
+            player_seed(),
+            *sk.verifying_key(),
+            vec![U256::from(1)],
+            CHAIN_ID,
+            CONTRACT,
+            0,
+        );
+        assert!(!sm.terminated());
+
+        let action = hit(&sm, 1);
+        let signature = sign(&sk, &action);
+        assert_eq!(sm.try_input(action, &signature), Err(ActionError::InvalidNonce(0)));
+
+        let action = hit(&sm, 0);
+        let signature = sign(&sk, &action);
+        sm.try_input(action.clone(), &signature).unwrap();
+        assert_eq!(sm.next_nonce(), 1);
+
+        // a resent action must not be applied twice
+        assert_eq!(sm.try_input(action, &signature), Err(ActionError::DuplicateNonce(1)));
+        assert_eq!(sm.next_nonce(), 1);
+    }
+
+    #[test]
+    fn test_check_signed_authenticates_without_playing() {
+        let sk = SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let sm = BlackjackStateMachine::new(
+            dealer_seed(),
+            player_seed(),
+            *sk.verifying_key(),
+            vec![U256::from(1)],
+            CHAIN_ID,
+            CONTRACT,
+            0,
+        );
+        let double = act(&sm, 0, ActionType::Double);
+        sm.check_signed(&double, &sign(&sk, &double)).unwrap();
+        assert_eq!(sm.next_nonce(), 0);
+
+        let stranger = SigningKey::from_slice(&[8u8; 32]).unwrap();
+        assert_eq!(
+            sm.check_signed(&double, &sign(&stranger, &double)),
+            Err(ActionError::InvalidSignature)
+        );
+        let other_hand = DeAction {
+            handId: 1,
+            ..double.clone()
+        };
+        assert_eq!(
+            sm.check_signed(&other_hand, &sign(&sk, &other_hand)),
+            Err(ActionError::InvalidHand)
+        );
+    }
+
+    #[test]
+    fn test_payment_pays_for_one_action() {
+        let sk = SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let mut sm = BlackjackStateMachine::new(
+            dealer_seed(),
+            player_seed(),
+            *sk.verifying_key(),
+            vec![U256::from(1)],
+            CHAIN_ID,
+            CONTRACT,
+            0,
+        );
+        let payment = B256::repeat_byte(1);
+
+        let action = act(&sm, 0, ActionType::Double);
+        let signature = sign(&sk, &action);
+        sm.try_paid_input(action, &signature, payment).unwrap();
+        assert_eq!(sm.bets(), &[U256::from(2)]);
+
+        let action = act(&sm, 1, ActionType::Double);
+        let signature = sign(&sk, &action);
+        assert_eq!(sm.try_paid_input(action, &signature, payment), Err(ActionError::PaymentReused));
+    }
+
+    #[test]
+    fn test_transcript_round_trip() {
+        let sk = SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let mut sm = BlackjackStateMachine::new(
+            dealer_seed(),
+            player_seed(),
+            *sk.verifying_key(),
+            vec![U256::from(1)],
+            CHAIN_ID,
+            CONTRACT,
+            0,
+        );
+        let action = hit(&sm, 0);
+        sm.try_input(action.clone(), &sign(&sk, &action)).unwrap();
+
+        // a game in progress is rebuilt to the same state, and keeps being played from there
+        let transcript = sm.transcript();
+        let mut rebuilt =
+            BlackjackStateMachine::from_transcript(dealer_seed(), &transcript, CHAIN_ID, CONTRACT)
+                .unwrap();
+        assert_eq!(rebuilt.transcript().abi_encode(), transcript.abi_encode());
+        assert_eq!(rebuilt.player_hands(), sm.player_hands());
+        assert_eq!(rebuilt.dealer_hand(), sm.dealer_hand());
+        // the fixed seeds leave the hand open after a hit
+        assert!(!sm.terminated());
+        let action = act(&sm, 1, ActionType::Stand);
+        let signature = sign(&sk, &action);
+        sm.try_input(action.clone(), &signature).unwrap();
+        rebuilt.try_input(action, &signature).unwrap();
+        let input = sm.extract().unwrap();
+        assert_eq!(rebuilt.extract().unwrap().abi_encode(), input.abi_encode());
+
+        let rebuilt =
+            BlackjackStateMachine::from_transcript(dealer_seed(), &input, CHAIN_ID, CONTRACT)
+                .unwrap();
+        assert!(rebuilt.terminated());
+        assert_eq!(rebuilt.winnings(), sm.winnings());
+        assert_eq!(rebuilt.dealer_hand(), sm.dealer_hand());
+
+        let mut forged = input.clone();
+        forged.signatures[0][1] = B256::ZERO;
+        assert_eq!(
+            BlackjackStateMachine::from_transcript(dealer_seed(), &forged, CHAIN_ID, CONTRACT)
+                .err(),
+            Some(TranscriptError::Action(0, ActionError::InvalidSignature))
+        );
+    }
+
+    #[test]
+    fn test_snapshot_resumes_at_the_same_card() {
+        let sk = SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let mut sm = BlackjackStateMachine::new(
+            dealer_seed(),
+            player_seed(),
+            *sk.verifying_key(),
+            vec![U256::from(1)],
+            CHAIN_ID,
+            CONTRACT,
+            0,
+        );
+        let action = hit(&sm, 0);
+        sm.try_input(action.clone(), &sign(&sk, &action)).unwrap();
+
+        let json = serde_json::to_string(&sm.snapshot()).unwrap();
+        let snapshot: Snapshot = serde_json::from_str(&json).unwrap();
+        let resume = |snapshot: Snapshot| {
+            BlackjackStateMachine::from_snapshot(dealer_seed(), snapshot, CHAIN_ID, CONTRACT)
+        };
+        let mut resumed = resume(snapshot.clone()).unwrap();
+        assert_eq!(resumed.snapshot(), sm.snapshot());
+
+        // both deal the same card from here on
+        assert!(!sm.terminated());
+        let action = hit(&sm, 1);
+        let signature = sign(&sk, &action);
+        sm.try_input(action.clone(), &signature).unwrap();
+        resumed.try_input(action, &signature).unwrap();
+        assert_eq!(resumed.player_hands(), sm.player_hands());
+        assert_eq!(resumed.snapshot(), sm.snapshot());
+
+        // current snapshots have to match their actions
+        let mut tampered = snapshot.clone();
+        tampered.game.word_pos += 1;
+        assert_eq!(resume(tampered).err(), Some(SnapshotError::InvalidState));
+        let mut forged = snapshot.clone();
+        forged.signatures[0].0[40] ^= 1;
+        assert_eq!(
+            resume(forged).err(),
+            Some(SnapshotError::Transcript(TranscriptError::Action(
+                0,
+                ActionError::InvalidSignature
+            )))
+        );
+
+        // older snapshots are replayed from their actions
+        let mut old = snapshot.clone();
+        old.version = 0;
+        old.game.word_pos = 0;
+        assert_eq!(resume(old).unwrap().snapshot(), snapshot);
+
+        let mut newer = snapshot.clone();
+        newer.version = SNAPSHOT_VERSION + 1;
+        assert_eq!(
+            resume(newer).err(),
+            Some(SnapshotError::UnsupportedVersion(SNAPSHOT_VERSION + 1))
+        );
+        assert_eq!(
+            BlackjackStateMachine::from_snapshot(player_seed(), snapshot, CHAIN_ID, CONTRACT).err(),
+            Some(SnapshotError::WrongDealerSeed)
+        );
+    }
+}
@@ -0,0 +1,447 @@
+//! Durable record of the games being played
+//!
+//! A game the dealer can't prove ends with the player reclaiming 2.5x of the bets and the dealer
+//! being banned. Everything needed to rebuild a game is therefore recorded before the player sees
+//! the outcome: the seeds, bets and key once it is dealt, then every accepted action with its
+//! signature.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use alloy_primitives::{Address, Bytes, FixedBytes, B256, U256};
+use alloy_sol_types::SolValue;
+use anyhow::{Context, Result};
+use k256::ecdsa::VerifyingKey;
+use k256::EncodedPoint;
+use serde::{Deserialize, Serialize};
+
+use crate::seeds::SeedManager;
+use crate::sm::{BlackjackStateMachine, DeAction, Games};
+
+pub trait GameStore: Send + Sync {
+    /// Records a game once it is dealt, before any action
+    fn start(&self, game: &StoredGame) -> Result<()>;
+    /// Records an action the game accepted
+    fn action(&self, game_id: u64, action: &StoredAction) -> Result<()>;
+    /// Records winnings paid out with `transferWinningsToUser` ahead of the proof
+    fn prepaid(&self, game_id: u64, amount: U256) -> Result<()>;
+    /// Records that `proveGames` settled a game, it isn't restored anymore
+    fn settled(&self, game_id: u64) -> Result<()>;
+    /// Games that aren't settled, with their actions in the order they were accepted
+    fn load(&self) -> Result<Vec<StoredGame>>;
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StoredGame {
+    pub game_id: u64,
+    /// Commitment of the dealer seed, the seed itself only lives in the encrypted seed store
+    pub dealer_commitment: B256,
+    pub player_seed: FixedBytes<16>,
+    /// SEC1 encoded public key of the player
+    pub player_pubkey: Bytes,
+    pub bets: Vec<U256>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub actions: Vec<StoredAction>,
+    /// Winnings paid out ahead of the proof
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prepaid: Option<U256>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StoredAction {
+    /// ABI encoded `DeAction`
+    pub action: Bytes,
+    pub signature: Bytes,
+    /// Transaction that paid for a `Double` or `Split`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payment: Option<B256>,
+}
+
+impl StoredAction {
+    pub fn new(action: &DeAction, signature: &[u8], payment: Option<B256>) -> Self {
+        Self {
+            action: action.abi_encode().into(),
+            signature: signature.to_vec().into(),
+            payment,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "snake_case")]
+enum Record {
+    Start(StoredGame),
+    Action { game_id: u64, action: StoredAction },
+    Prepaid { game_id: u64, amount: U256 },
+    Settled { game_id: u64 },
+}
+
+/// Append-only file with one JSON record per line. Every record is synced to disk before it is
+/// acknowledged.
+pub struct FileStore {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl FileStore {
+    /// Opens the store and compacts it down to the unsettled games, so that it doesn't grow with
+    /// every game ever dealt
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let file = open_log(&path)?;
+        let store = Self {
+            path,
+            file: Mutex::new(file),
+        };
+        let mut compacted = Vec::new();
+        // a game's actions and prepaid winnings go along in its `Start` record
+        for game in store.load()? {
+            serde_json::to_writer(&mut compacted, &Record::Start(game))?;
+            compacted.push(b'\n');
+        }
+        replace_file(&store.path, &compacted)?;
+        *store.file.lock().unwrap() = open_log(&store.path)?;
+        Ok(store)
+    }
+
+    fn append(&self, record: &Record) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&line)?;
+        file.sync_data()?;
+        Ok(())
+    }
+}
+
+impl GameStore for FileStore {
+    fn start(&self, game: &StoredGame) -> Result<()> {
+        self.append(&Record::Start(game.clone()))
+    }
+
+    fn action(&self, game_id: u64, action: &StoredAction) -> Result<()> {
+        self.append(&Record::Action {
+            game_id,
+            action: action.clone(),
+        })
+    }
+
+    fn prepaid(&self, game_id: u64, amount: U256) -> Result<()> {
+        self.append(&Record::Prepaid {
+            game_id,
+            amount,
+        })
+    }
+
+    fn settled(&self, game_id: u64) -> Result<()> {
+        self.append(&Record::Settled {
+            game_id,
+        })
+    }
+
+    fn load(&self) -> Result<Vec<StoredGame>> {
+        let _guard = self.file.lock().unwrap();
+        let contents = std::fs::read_to_string(&self.path)?;
+        let mut games = BTreeMap::new();
+        let mut settled = HashSet::new();
+        for (i, line) in contents.lines().enumerate() {
+            let record = serde_json::from_str(line)
+                .with_context(|| format!("{}:{}", self.path.display(), i + 1))?;
+            match record {
+                // a `/start` racing the settlement can record a game after it was settled
+                Record::Start(game) if settled.contains(&game.game_id) => {}
+                Record::Start(game) => {
+                    // concurrent starts of a game can both be recorded, with the same contents
+                    games.entry(game.game_id).or_insert(game);
+                }
+                Record::Action {
+                    game_id,
+                    action,
+                } => match games.get_mut(&game_id) {
+                    Some(game) => game.actions.push(action),
+                    None => {
+                        log::warn!("action of unknown game {} in {}", game_id, self.path.display())
+                    }
+                },
+                Record::Prepaid {
+                    game_id,
+                    amount,
+                } => match games.get_mut(&game_id) {
+                    Some(game) => game.prepaid = Some(amount),
+                    None => {
+                        log::warn!("payout of unknown game {} in {}", game_id, self.path.display())
+                    }
+                },
+                Record::Settled {
+                    game_id,
+                } => {
+                    games.remove(&game_id);
+                    settled.insert(game_id);
+                }
+            }
+        }
+        Ok(games.into_values().collect())
+    }
+}
+
+/// File the block the contract's events are followed from is saved in, so that a restart
+/// doesn't scan the chain from the start again
+pub struct BlockCursor {
+    path: PathBuf,
+}
+
+impl BlockCursor {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+        }
+    }
+
+    /// The saved block, `None` until one is saved
+    pub fn load(&self) -> Result<Option<u64>> {
+        match std::fs::read_to_string(&self.path) {
+            Ok(contents) => Ok(Some(
+                contents
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("parsing {}", self.path.display()))?,
+            )),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("reading {}", self.path.display())),
+        }
+    }
+
+    /// Replaces the saved block
+    pub fn save(&self, block: u64) -> Result<()> {
+        replace_file(&self.path, format!("{}\n", block).as_bytes())
+    }
+}
+
+/// Writes `contents` to a new file and renames it over `path`, so a crash leaves either the old
+/// or the new contents
+fn replace_file(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp).with_context(|| format!("creating {}", tmp.display()))?;
+    file.write_all(contents)?;
+    file.sync_data()?;
+    std::fs::rename(&tmp, path).with_context(|| format!("replacing {}", path.display()))?;
+    Ok(())
+}
+
+/// Opens a file of JSON lines for appending, creating it if needed
+pub(crate) fn open_log(path: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("opening {}", path.display()))?;
+    // a crash in the middle of a write leaves a partial line, whose record was never
+    // acknowledged. Drop it so that the next record starts on a line of its own.
+    let contents = std::fs::read(path)?;
+    if contents.last().is_some_and(|&last| last != b'\n') {
+        let complete = contents.iter().rposition(|&byte| byte == b'\n').map_or(0, |i| i + 1);
+        log::warn!("dropping a partial record at the end of {}", path.display());
+        file.set_len(complete as u64)?;
+    }
+    Ok(file)
+}
+
+/// Rebuilds the unsettled games of `store` by replaying their actions
+pub fn restore(
+    store: &dyn GameStore,
+    seeds: &SeedManager,
+    chain_id: u64,
+    contract: Address,
+) -> Result<Games> {
+    let mut games = Games::default();
+    for game in store.load()? {
+        let Some(dealer_seed) = seeds.get(&game.dealer_commitment.0) else {
+            log::error!(
+                "game {} can't be restored, the seed of {} is gone",
+                game.game_id,
+                game.dealer_commitment
+            );
+            continue;
+        };
+        let game_id = game.game_id;
+        // a record this dealer can't read shouldn't keep it from dealing every other game
+        match replay(game, dealer_seed, chain_id, contract) {
+            Ok(sm) => {
+                games.get_mut().insert(game_id, Arc::new(tokio::sync::Mutex::new(sm)));
+            }
+            Err(err) => log::error!("game {} can't be restored: {:?}", game_id, err),
+        }
+    }
+    Ok(games)
+}
+
+/// Rebuilds a game by replaying its actions. Fails on an undecodable record or an action the
+/// state machine rejects, a game restored short of its recorded actions would be dealt again
+/// from an earlier point.
+fn replay(
+    game: StoredGame,
+    dealer_seed: [u8; 16],
+    chain_id: u64,
+    contract: Address,
+) -> Result<BlackjackStateMachine> {
+    let player_pubkey =
+        VerifyingKey::from_encoded_point(&EncodedPoint::from_bytes(&game.player_pubkey)?)?;
+    let mut sm = BlackjackStateMachine::new(
+        dealer_seed,
+        game.player_seed.0,
+        player_pubkey,
+        game.bets,
+        chain_id,
+        contract,
+        game.game_id,
+    );
+    for stored in game.actions {
+        let action = DeAction::abi_decode(&stored.action, true)?;
+        let nonce = action.nonce;
+        let replayed = match stored.payment {
+            Some(payment) => sm.try_paid_input(action, &stored.signature, payment),
+            None => sm.try_input(action, &stored.signature),
+        };
+        replayed.map_err(|err| anyhow::anyhow!("replaying action {} failed: {}", nonce, err))?;
+    }
+    Ok(sm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn game(game_id: u64) -> StoredGame {
+        StoredGame {
+            game_id,
+            dealer_commitment: B256::repeat_byte(1),
+            player_seed: FixedBytes::repeat_byte(2),
+            player_pubkey: vec![4; 65].into(),
+            bets: vec![U256::from(1)],
+            actions: Vec::new(),
+            prepaid: None,
+        }
+    }
+
+    fn action(nonce: u8) -> StoredAction {
+        let action = DeAction {
+            nonce,
+            handId: 0,
+            inner: 0,
+            my_cards: vec![3, 4],
+            dealer_cards: vec![5],
+        };
+        StoredAction::new(&action, &[nonce; 64], Some(B256::repeat_byte(nonce)))
+    }
+
+    #[test]
+    fn test_file_store_keeps_unsettled_games() {
+        let path = std::env::temp_dir().join(format!("games-{}.jsonl", std::process::id()));
+        let store = FileStore::open(&path).unwrap();
+        store.start(&game(1)).unwrap();
+        store.start(&game(2)).unwrap();
+        store.action(1, &action(0)).unwrap();
+        store.action(2, &action(0)).unwrap();
+        store.action(1, &action(1)).unwrap();
+        store.prepaid(1, U256::from(3)).unwrap();
+        store.settled(2).unwrap();
+        // recorded by a `/start` that raced the settlement
+        store.start(&game(2)).unwrap();
+
+        // a torn write is dropped when reopening
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"record\":\"act")
+            .unwrap();
+        let store = FileStore::open(&path).unwrap();
+        store.start(&game(3)).unwrap();
+        // compacted down to game 1 with its actions, then game 3
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+
+        let expected = vec![
+            StoredGame {
+                actions: vec![action(0), action(1)],
+                prepaid: Some(U256::from(3)),
+                ..game(1)
+            },
+            game(3),
+        ];
+        assert_eq!(store.load().unwrap(), expected);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_block_cursor_keeps_the_last_block() {
+        let path = std::env::temp_dir().join(format!("cursor-{}", std::process::id()));
+        let cursor = BlockCursor::new(&path);
+        assert_eq!(cursor.load().unwrap(), None);
+        cursor.save(17).unwrap();
+        cursor.save(42).unwrap();
+        assert_eq!(BlockCursor::new(&path).load().unwrap(), Some(42));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_restore_skips_unreadable_games() {
+        let id = std::process::id();
+        let path = std::env::temp_dir().join(format!("restore-{}.jsonl", id));
+        let dir = std::env::temp_dir().join(format!("restore-seeds-{}", id));
+        let seeds = SeedManager::open(&dir, "password").unwrap();
+        let dealer_commitment = B256::from(seeds.generate().unwrap());
+        let pubkey = k256::ecdsa::SigningKey::from_slice(&[7; 32]).unwrap();
+        let readable = StoredGame {
+            dealer_commitment,
+            player_pubkey: pubkey
+                .verifying_key()
+                .to_encoded_point(false)
+                .as_bytes()
+                .to_vec()
+                .into(),
+            ..game(1)
+        };
+
+        let store = FileStore::open(&path).unwrap();
+        store.start(&readable).unwrap();
+        // not a point on the curve
+        store
+            .start(&StoredGame {
+                dealer_commitment,
+                ..game(2)
+            })
+            .unwrap();
+        store
+            .start(&StoredGame {
+                game_id: 3,
+                ..readable.clone()
+            })
+            .unwrap();
+        store
+            .action(
+                3,
+                &StoredAction {
+                    action: vec![1, 2, 3].into(),
+                    ..action(0)
+                },
+            )
+            .unwrap();
+        // not signed by the player
+        store
+            .start(&StoredGame {
+                game_id: 4,
+                ..readable.clone()
+            })
+            .unwrap();
+        store.action(4, &action(0)).unwrap();
+
+        let games = restore(&store, &seeds, 1, Address::ZERO).unwrap().into_inner();
+        assert_eq!(games.keys().copied().collect::<Vec<_>>(), vec![1]);
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,34 +1,79 @@
-#![allow(dead_code)]
-#![allow(unused_variables)]
-
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::sync::Arc;
 
-use axum::extract::State;
+use alloy_primitives::U256;
+use alloy_sol_types::{sol, SolStruct, SolValue};
+use axum::extract::{Path, Query, State};
 use axum::http::StatusCode;
-use axum::routing::post;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
 use axum::{Json, Router};
-use tokio::sync::{Mutex, RwLock};
+use k256::ecdsa::signature::hazmat::PrehashVerifier;
+use k256::ecdsa::{Signature, VerifyingKey};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::Mutex;
+
+use blackjack_core::{action_domain, commitment};
 
-use crate::eth::Blockchain;
-use crate::sm::BlackjackStateMachine;
+use crate::archive::GameArchive;
+use crate::eth::{Blockchain, PaymentData};
+use crate::payout::Payout;
+use crate::registry::GameRegistry;
+use crate::seeds::SeedManager;
+use crate::sm::{ActionError, ActionType, BlackjackStateMachine, DeAction, Games};
+use crate::store::{GameStore, StoredAction, StoredGame};
 
+/// Everything the player API works with
 #[derive(Clone)]
-struct AppState {
-    sm: Arc<RwLock<HashMap<u64, Mutex<BlackjackStateMachine>>>>,
-    eth: Arc<Blockchain>,
-    my_seed: [u8; 16],
-}
-
-pub async fn web_task(host: &str, eth: Arc<Blockchain>, my_seed: [u8; 16]) {
-    let state_machines = Arc::new(RwLock::new(HashMap::<u64, Mutex<BlackjackStateMachine>>::new()));
-    let state = AppState {
-        sm: state_machines,
-        eth,
-        my_seed,
-    };
-    let app =
-        Router::new().route("/start", post(start)).route("/action", post(action)).with_state(state);
+pub struct AppState {
+    /// Live games, shared with `settle_task`
+    pub sm: Arc<Games>,
+    pub eth: Arc<Blockchain>,
+    /// Games as seen on chain, kept up to date by `eth_task`
+    pub registry: Arc<GameRegistry>,
+    /// Winnings of terminated games go here to be paid out ahead of the proof
+    pub payouts: Option<UnboundedSender<Payout>>,
+    pub seeds: Arc<SeedManager>,
+    /// Every game and accepted action is recorded here before the player sees the outcome
+    pub store: Arc<dyn GameStore>,
+    /// Settled games, which are no longer in `sm`
+    pub archive: Arc<GameArchive>,
+}
+
+impl AppState {
+    fn pay_out(&self, game_id: u64, sm: &BlackjackStateMachine) {
+        if let Some(payouts) = &self.payouts {
+            let payout = Payout {
+                game_id,
+                amount: sm.winnings(),
+            };
+            if payouts.send(payout).is_err() {
+                log::warn!("payout task is gone, game {} is left to the proof", game_id);
+            }
+        }
+    }
+
+    /// `StartResponse` of a game that was settled already, if it was
+    fn archived_start(&self, game_id: u64) -> Result<Option<StartResponse>, ApiError> {
+        let archived = self.archive.get(game_id).map_err(|err| {
+            log::error!("reading game {} from the archive failed: {:?}", game_id, err);
+            ApiError::Internal
+        })?;
+        Ok(archived.map(|game| StartResponse {
+            player_hands: game.snapshot.game.player_hands.clone(),
+            dealer_hand: game.visible_dealer_hand().to_vec(),
+            hands_active: game.snapshot.game.hands_active.clone(),
+            game_index: game_id,
+        }))
+    }
+}
+
+pub async fn web_task(host: &str, state: AppState) {
+    let app = Router::new()
+        .route("/start", post(start))
+        .route("/action", post(action))
+        .route("/game/:id", get(game))
+        .with_state(state);
     let listener = tokio::net::TcpListener::bind(&host).await.unwrap();
     axum::serve(listener, app).await.unwrap();
 }
@@ -44,6 +89,7 @@ struct StartRequest {
 #[serde(rename_all = "camelCase")]
 struct StartResponse {
     player_hands: Vec<Vec<u8>>,
+    /// Only the upcard, unless the round ended right after the deal
     dealer_hand: Vec<u8>,
     hands_active: Vec<bool>,
     game_index: u64,
@@ -52,32 +98,94 @@ struct StartResponse {
 async fn start(
     State(state): State<AppState>,
     Json(payload): Json<StartRequest>,
-) -> Result<(StatusCode, Json<StartResponse>), StatusCode> {
-    let player_seed = hex::decode(&payload.player_seed)
-        .map_err(|_| StatusCode::BAD_REQUEST)?
+) -> Result<(StatusCode, Json<StartResponse>), ApiError> {
+    let player_seed: [u8; 16] = hex::decode(payload.player_seed.trim_start_matches("0x"))
+        .map_err(|_| ApiError::InvalidSeed)?
         .try_into()
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
-    let start =
-        state.eth.get_start_tx(&payload.tx_hash).await.map_err(|_| StatusCode::BAD_REQUEST)?;
-    state.sm.write().await.insert(
-        start.game_index,
-        Mutex::new(BlackjackStateMachine::new(
-            state.my_seed,
-            player_seed,
-            start.player_pubkey,
-            start.bets,
-        )),
-    );
-
-    let read_ref = state.sm.read().await;
-    let sm = read_ref.get(&start.game_index).unwrap().lock().await;
+        .map_err(|_| ApiError::InvalidSeed)?;
+    let starts = state.eth.get_start_tx(&payload.tx_hash).await.map_err(|err| {
+        log::warn!("invalid start tx {}: {:?}", payload.tx_hash, err);
+        ApiError::InvalidStartTx
+    })?;
+    // the guest commits sha256 of the seed, a game with a different seed could never be settled.
+    // This also picks the player's game out of a transaction starting several.
+    let start = starts
+        .into_iter()
+        .find(|start| commitment(&player_seed)[..] == start.player_commitment[..])
+        .ok_or(ApiError::CommitmentMismatch)?;
+    // a repeated `/start` must not reset a game in progress, it just returns its current state
+    if let Some(response) = state.archived_start(start.game_index)? {
+        return Ok((StatusCode::OK, Json(response)));
+    }
+    // the event monitor may not have caught up with the start transaction yet
+    state.registry.insert(start.clone()).await;
+    let existing = state.sm.read().await.get(&start.game_index).cloned();
+    let (sm, created) = match existing {
+        Some(sm) => (sm, false),
+        None => {
+            // the game is dealt with the seed the dealer was committed to when it started. It is
+            // only needed for new games, the seeds of settled ones get deleted.
+            let dealer_seed = state.seeds.get(&start.dealer_commitment.0).ok_or_else(|| {
+                log::error!(
+                    "no seed for commitment {} of game {}",
+                    start.dealer_commitment,
+                    start.game_index
+                );
+                ApiError::SeedUnavailable
+            })?;
+            let game = StoredGame {
+                game_id: start.game_index,
+                dealer_commitment: start.dealer_commitment,
+                player_seed: player_seed.into(),
+                player_pubkey: start
+                    .player_pubkey
+                    .to_encoded_point(false)
+                    .as_bytes()
+                    .to_vec()
+                    .into(),
+                bets: start.bets.clone(),
+                actions: Vec::new(),
+                prepaid: None,
+            };
+            // recorded before anyone sees the deal, and without holding up the other games
+            persist(&state.store, move |store| store.start(&game)).await.map_err(|err| {
+                log::error!("recording game {} failed: {:?}", start.game_index, err);
+                ApiError::Internal
+            })?;
+            let sm = BlackjackStateMachine::new(
+                dealer_seed,
+                player_seed,
+                start.player_pubkey,
+                start.bets,
+                state.eth.chain_id(),
+                state.eth.contract(),
+                start.game_index,
+            );
+            let mut games = state.sm.write().await;
+            // `retire` archives a game before it removes it, a game settled since the check
+            // above is in the archive by now
+            if let Some(response) = state.archived_start(start.game_index)? {
+                return Ok((StatusCode::OK, Json(response)));
+            }
+            match games.entry(start.game_index) {
+                // a concurrent `/start` of the same game got here first
+                Entry::Occupied(entry) => (entry.get().clone(), false),
+                Entry::Vacant(entry) => (entry.insert(Arc::new(Mutex::new(sm))).clone(), true),
+            }
+        }
+    };
+    let sm = sm.lock().await;
+    // the round can be over right after the deal
+    if created && sm.terminated() {
+        state.pay_out(start.game_index, &sm);
+    }
 
     Ok((
         StatusCode::OK,
         Json(StartResponse {
-            player_hands: sm.player_hands.clone(),
-            dealer_hand: sm.dealer_hand.clone(),
-            hands_active: sm.hands_active.clone(),
+            player_hands: sm.player_hands().to_vec(),
+            dealer_hand: sm.visible_dealer_hand().to_vec(),
+            hands_active: sm.hands_active().to_vec(),
             game_index: start.game_index,
         }),
     ))
@@ -86,8 +194,11 @@ async fn start(
 #[derive(serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct ActionRequest {
+    game_index: u64,
+    /// ABI-encoded `DeAction`
     action: Vec<u8>,
     signature: Vec<u8>,
+    /// `double` or `split` transaction paying for a `Double` or `Split`
     tx_hash: Option<String>,
 }
 
@@ -95,11 +206,340 @@ struct ActionRequest {
 #[serde(rename_all = "camelCase")]
 struct ActionResponse {
     player_hands: Vec<Vec<u8>>,
+    /// Only the upcard while any hand is active
+    dealer_hand: Vec<u8>,
+    hands_active: Vec<bool>,
+    /// Nonce the player's next action has to carry
+    next_nonce: u8,
+    /// Total payout in ether, only present once every hand is resolved
+    winnings: Option<f64>,
+}
+
+async fn action(
+    State(state): State<AppState>,
+    Json(payload): Json<ActionRequest>,
+) -> Result<(StatusCode, Json<ActionResponse>), ApiError> {
+    let action =
+        DeAction::abi_decode(&payload.action, true).map_err(|_| ApiError::InvalidAction)?;
+    let sm = state.sm.read().await.get(&payload.game_index).cloned();
+    let Some(sm) = sm else {
+        // settled games are only in the archive
+        return match state.archive.get(payload.game_index) {
+            Ok(Some(_)) => Err(ActionError::GameTerminated.into()),
+            Ok(None) => Err(ApiError::GameNotFound),
+            Err(err) => {
+                log::error!(
+                    "reading game {} from the archive failed: {:?}",
+                    payload.game_index,
+                    err
+                );
+                Err(ApiError::Internal)
+            }
+        };
+    };
+    // the payment is read from the chain without holding the game's lock, so that a slow node
+    // doesn't hold up the game. Only requests the player signed get that far.
+    let payments = match ActionType::try_from(action.inner) {
+        Ok(kind @ (ActionType::Double | ActionType::Split)) => {
+            sm.lock().await.check_signed(&action, &payload.signature)?;
+            Some(verify_payment(&state, &payload, &action, kind).await?)
+        }
+        _ => None,
+    };
+
+    let mut sm = sm.lock().await;
+    // the action is played on a copy, the game only moves on once the action is on disk
+    let mut staged = sm.clone();
+    let stored = match payments {
+        Some(payments) => {
+            // the extra card is only dealt once the player paid the hand's bet. The game may
+            // have moved on while the payment was read.
+            staged.check_nonce(action.nonce)?;
+            let bet = staged.bets().get(action.handId as usize);
+            let payment = payments
+                .into_iter()
+                .find(|payment| Some(&payment.value) == bet)
+                .ok_or(ApiError::InvalidPayment)?
+                .tx_hash;
+            let stored = StoredAction::new(&action, &payload.signature, Some(payment));
+            staged.try_paid_input(action, &payload.signature, payment)?;
+            stored
+        }
+        None => {
+            let stored = StoredAction::new(&action, &payload.signature, None);
+            staged.try_input(action, &payload.signature)?;
+            stored
+        }
+    };
+    // without its signature the game can only be proven as not terminated
+    let game_id = payload.game_index;
+    persist(&state.store, move |store| store.action(game_id, &stored)).await.map_err(|err| {
+        log::error!("recording an action of game {} failed: {:?}", game_id, err);
+        ApiError::Internal
+    })?;
+    *sm = staged;
+
+    let winnings = if sm.terminated() {
+        state.pay_out(payload.game_index, &sm);
+        Some(to_ether(sm.winnings())?)
+    } else {
+        None
+    };
+
+    Ok((
+        StatusCode::OK,
+        Json(ActionResponse {
+            player_hands: sm.player_hands().to_vec(),
+            dealer_hand: sm.visible_dealer_hand().to_vec(),
+            hands_active: sm.hands_active().to_vec(),
+            next_nonce: sm.next_nonce(),
+            winnings,
+        }),
+    ))
+}
+
+sol! {
+    /// Request for the state of a game. The player signs it with the game's key, in the domain of
+    /// the game's actions.
+    struct StateRequest {
+        uint64 timestamp;
+    }
+}
+
+/// How far the timestamp of a `StateRequest` may be from the dealer's clock, in seconds
+const STATE_REQUEST_WINDOW: u64 = 300;
+
+#[derive(serde::Deserialize)]
+struct GameQuery {
+    /// Unix time the request was signed at
+    timestamp: u64,
+    /// Hex-encoded signature of the `StateRequest`
+    signature: String,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GameResponse {
+    player_hands: Vec<Vec<u8>>,
+    /// Only the upcard while any hand is active
     dealer_hand: Vec<u8>,
     hands_active: Vec<bool>,
-    winnings: Option<f64>
+    /// Hand the next action has to be for, absent once every hand is resolved
+    hand_id: Option<u8>,
+    /// Nonce the player's next action has to carry
+    next_nonce: u8,
+    /// Total payout in ether, only present once every hand is resolved
+    winnings: Option<f64>,
+    /// Whether the game was settled on chain
+    settled: bool,
+}
+
+/// State of a game as the player sees it, for clients that lost track of it
+async fn game(
+    State(state): State<AppState>,
+    Path(game_id): Path<u64>,
+    Query(query): Query<GameQuery>,
+) -> Result<(StatusCode, Json<GameResponse>), ApiError> {
+    let sm = state.sm.read().await.get(&game_id).cloned();
+    if let Some(sm) = sm {
+        let sm = sm.lock().await;
+        authenticate(&state, game_id, sm.player_pubkey(), &query)?;
+        return Ok((
+            StatusCode::OK,
+            Json(GameResponse {
+                player_hands: sm.player_hands().to_vec(),
+                dealer_hand: sm.visible_dealer_hand().to_vec(),
+                hands_active: sm.hands_active().to_vec(),
+                hand_id: sm.current_hand(),
+                next_nonce: sm.next_nonce(),
+                winnings: sm.terminated().then(|| to_ether(sm.winnings())).transpose()?,
+                settled: false,
+            }),
+        ));
+    }
+
+    let game = state
+        .archive
+        .get(game_id)
+        .map_err(|err| {
+            log::error!("reading game {} from the archive failed: {:?}", game_id, err);
+            ApiError::Internal
+        })?
+        .ok_or(ApiError::GameNotFound)?;
+    let pubkey =
+        VerifyingKey::from_sec1_bytes(game.player_pubkey()).map_err(|_| ApiError::Internal)?;
+    authenticate(&state, game_id, &pubkey, &query)?;
+    Ok((
+        StatusCode::OK,
+        Json(GameResponse {
+            player_hands: game.snapshot.game.player_hands.clone(),
+            dealer_hand: game.visible_dealer_hand().to_vec(),
+            hands_active: game.snapshot.game.hands_active.clone(),
+            hand_id: game.current_hand(),
+            next_nonce: game.snapshot.actions.len() as u8,
+            winnings: game.terminated().then(|| to_ether(game.winnings())).transpose()?,
+            settled: true,
+        }),
+    ))
+}
+
+/// Checks that the player of the game signed the `StateRequest` of the query recently
+fn authenticate(
+    state: &AppState,
+    game_id: u64,
+    pubkey: &VerifyingKey,
+    query: &GameQuery,
+) -> Result<(), ApiError> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_err(|_| ApiError::Internal)?
+        .as_secs();
+    if query.timestamp.abs_diff(now) > STATE_REQUEST_WINDOW {
+        return Err(ApiError::ExpiredRequest);
+    }
+    let signature = hex::decode(query.signature.trim_start_matches("0x"))
+        .ok()
+        .and_then(|signature| Signature::from_slice(&signature).ok())
+        .ok_or(ActionError::InvalidSignature)?;
+    let domain =
+        action_domain(U256::from(state.eth.chain_id()), state.eth.contract(), U256::from(game_id));
+    let request = StateRequest {
+        timestamp: query.timestamp,
+    };
+    pubkey
+        .verify_prehash(request.eip712_signing_hash(&domain).as_slice(), &signature)
+        .map_err(|_| ActionError::InvalidSignature)?;
+    Ok(())
+}
+
+fn to_ether(amount: U256) -> Result<f64, ApiError> {
+    let amount = ethers::utils::format_ether(ethers::types::U256::from_little_endian(
+        &amount.to_le_bytes::<32>(),
+    ));
+    amount.parse::<f64>().map_err(|_| ApiError::Internal)
+}
+
+/// Runs a write to the store on the blocking pool, it waits for the disk
+async fn persist(
+    store: &Arc<dyn GameStore>,
+    write: impl FnOnce(&dyn GameStore) -> anyhow::Result<()> + Send + 'static,
+) -> anyhow::Result<()> {
+    let store = store.clone();
+    tokio::task::spawn_blocking(move || write(&*store)).await?
+}
+
+/// Payments of the action's hand in the request's payment transaction. Whether one of them
+/// matches the bet can only be checked under the game's lock.
+async fn verify_payment(
+    state: &AppState,
+    payload: &ActionRequest,
+    action: &DeAction,
+    kind: ActionType,
+) -> Result<Vec<PaymentData>, ApiError> {
+    let tx_hash = payload.tx_hash.as_deref().ok_or(ApiError::PaymentMissing)?;
+    let payments = state.eth.get_payment_tx(tx_hash).await.map_err(|err| {
+        log::warn!("invalid payment tx {}: {:?}", tx_hash, err);
+        ApiError::InvalidPayment
+    })?;
+    let payments = payments
+        .into_iter()
+        .filter(|payment| {
+            payment.game_id == payload.game_index
+                && payment.hand == action.handId
+                && payment.kind == kind
+        })
+        .collect::<Vec<_>>();
+    if payments.is_empty() {
+        return Err(ApiError::InvalidPayment);
+    }
+    Ok(payments)
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ErrorResponse {
+    error: String,
+    /// Set when the action was rejected for its nonce, so the client can resynchronize
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_nonce: Option<u8>,
+}
+
+#[derive(Debug)]
+enum ApiError {
+    /// Player seed is not 16 hex-encoded bytes
+    InvalidSeed,
+    /// Transaction is not a `startGame` call with this dealer
+    InvalidStartTx,
+    /// Revealed player seed doesn't match the commitment from `startGame`
+    CommitmentMismatch,
+    /// Request body couldn't be decoded
+    InvalidAction,
+    GameNotFound,
+    /// `Double` or `Split` without a payment transaction
+    PaymentMissing,
+    /// Payment transaction isn't a successful `double` or `split` of the hand with its bet
+    InvalidPayment,
+    /// Dealer doesn't have the seed it was committed to when the game started
+    SeedUnavailable,
+    /// Timestamp of a signed request is too far from the dealer's clock
+    ExpiredRequest,
+    /// State machine rejected the action
+    Rejected(ActionError),
+    Internal,
+}
+
+impl From<ActionError> for ApiError {
+    fn from(value: ActionError) -> Self {
+        ApiError::Rejected(value)
+    }
 }
 
-async fn action(Json(payload): Json<ActionRequest>) -> (StatusCode, Json<ActionResponse>) {
-    todo!()
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let next_nonce = match self {
+            ApiError::Rejected(
+                ActionError::DuplicateNonce(expected) | ActionError::InvalidNonce(expected),
+            ) => Some(expected),
+            _ => None,
+        };
+        let (status, error) = match self {
+            ApiError::InvalidSeed => (StatusCode::BAD_REQUEST, "invalid player seed".into()),
+            ApiError::InvalidStartTx => {
+                (StatusCode::BAD_REQUEST, "invalid start transaction".into())
+            }
+            ApiError::CommitmentMismatch => (
+                StatusCode::BAD_REQUEST,
+                "player seed doesn't match the on-chain commitment".into(),
+            ),
+            ApiError::InvalidAction => (StatusCode::BAD_REQUEST, "invalid action encoding".into()),
+            ApiError::GameNotFound => (StatusCode::NOT_FOUND, "game not found".into()),
+            ApiError::SeedUnavailable => {
+                (StatusCode::SERVICE_UNAVAILABLE, "dealer can't deal this game".into())
+            }
+            ApiError::ExpiredRequest => (StatusCode::UNAUTHORIZED, "request expired".into()),
+            ApiError::PaymentMissing => {
+                (StatusCode::PAYMENT_REQUIRED, "payment transaction required".into())
+            }
+            ApiError::InvalidPayment => (
+                StatusCode::PAYMENT_REQUIRED,
+                "payment transaction doesn't match the action".into(),
+            ),
+            ApiError::Rejected(err @ ActionError::InvalidSignature) => {
+                (StatusCode::UNAUTHORIZED, err.to_string())
+            }
+            ApiError::Rejected(
+                err @ (ActionError::GameTerminated | ActionError::DuplicateNonce(_)),
+            ) => (StatusCode::CONFLICT, err.to_string()),
+            ApiError::Rejected(err) => (StatusCode::UNPROCESSABLE_ENTITY, err.to_string()),
+            ApiError::Internal => (StatusCode::INTERNAL_SERVER_ERROR, "internal error".into()),
+        };
+        (
+            status,
+            Json(ErrorResponse {
+                error,
+                next_nonce,
+            }),
+        )
+            .into_response()
+    }
 }
@@ -0,0 +1,253 @@
+//! Differential test of `BlackjackStateMachine` against the zkVM guest.
+//!
+//! Random games are played through the dealer's state machine, then their transcripts are
+//! executed by the guest. Whatever the dealer tells the player has to match what gets proven.
+//! Games are also left unfinished, and transcripts tampered with the way a player could through
+//! `provideActions`, in which case the guest has to reject exactly what the dealer rejects.
+
+use alloy_primitives::{Address, B256, U256};
+use alloy_sol_types::SolValue;
+use app::sm::{ActionType, BlackjackStateMachine, DeAction, GameInput, Input, Output};
+use blackjack_core::{action_domain, action_signing_hash, actions_hash};
+use k256::ecdsa::signature::hazmat::PrehashSigner;
+use k256::ecdsa::{Signature, SigningKey, VerifyingKey};
+use methods::BLACKJACK_ELF;
+use proptest::prelude::*;
+use risc0_zkvm::{default_executor, ExecutorEnv};
+
+const CHAIN_ID: u64 = 11155111;
+const CONTRACT: Address = Address::repeat_byte(0x42);
+
+#[derive(Debug, Clone)]
+struct GameCase {
+    player_seed: [u8; 16],
+    bets: Vec<u64>,
+    /// Index into the legal actions at every step. Once exhausted, the player stands.
+    choices: Vec<u8>,
+    ending: Ending,
+}
+
+/// How the transcript handed to the guest comes about
+#[derive(Debug, Clone)]
+enum Ending {
+    /// Played until every hand is resolved
+    Complete,
+    /// Player stops once the choices are exhausted, the game may not be terminated
+    Abandoned,
+    /// Action at the index (modulo the number of actions) carries the wrong nonce, re-signed
+    BadNonce(usize),
+    /// Action at the index (modulo the number of actions) is signed by another key
+    ForeignSignature(usize),
+}
+
+/// Result of a game as seen by the dealer
+#[derive(Debug)]
+struct Expected {
+    payout: U256,
+    doubles: Vec<u8>,
+    splits: Vec<u8>,
+    terminated: bool,
+    action_hash: B256,
+}
+
+fn ending() -> impl Strategy<Value = Ending> {
+    prop_oneof![
+        Just(Ending::Complete),
+        Just(Ending::Abandoned),
+        any::<usize>().prop_map(Ending::BadNonce),
+        any::<usize>().prop_map(Ending::ForeignSignature),
+    ]
+}
+
+fn game_case() -> impl Strategy<Value = GameCase> {
+    (
+        any::<[u8; 16]>(),
+        prop::collection::vec(1u64..1_000_000, 1..=3),
+        prop::collection::vec(any::<u8>(), 0..12),
+        ending(),
+    )
+        .prop_map(|(player_seed, bets, choices, ending)| GameCase {
+            player_seed,
+            bets,
+            choices,
+            ending,
+        })
+}
+
+/// Actions allowed for the current hand. `Stand` goes first so that shrinking ends on it.
+fn legal_actions(sm: &BlackjackStateMachine) -> (usize, Vec<ActionType>) {
+    let hand_id = sm.hands_active().iter().position(|&active| active).unwrap();
+    let hand = &sm.player_hands()[hand_id];
+    let mut actions = vec![ActionType::Stand, ActionType::Hit];
+    if hand.len() == 2 {
+        actions.push(ActionType::Double);
+        if hand[0] == hand[1] && sm.player_hands().len() < 4 {
+            actions.push(ActionType::Split);
+        }
+    }
+    (hand_id, actions)
+}
+
+fn play(
+    dealer_seed: [u8; 16],
+    game_id: u64,
+    case: &GameCase,
+    sk: &SigningKey,
+) -> BlackjackStateMachine {
+    let bets = case.bets.iter().map(|&bet| U256::from(bet)).collect();
+    let mut sm = BlackjackStateMachine::new(
+        dealer_seed,
+        case.player_seed,
+        *sk.verifying_key(),
+        bets,
+        CHAIN_ID,
+        CONTRACT,
+        game_id,
+    );
+    let mut choices = case.choices.iter();
+    let mut nonce = 0u8;
+    while !sm.terminated() {
+        let (hand_id, actions) = legal_actions(&sm);
+        let inner = match (choices.next(), &case.ending) {
+            (Some(&c), _) => actions[c as usize % actions.len()],
+            (None, Ending::Abandoned) => break,
+            (None, _) => ActionType::Stand,
+        };
+        let action = DeAction {
+            nonce,
+            handId: hand_id as u8,
+            inner: inner.into(),
+            my_cards: sm.player_hands()[hand_id].clone(),
+            dealer_cards: sm.visible_dealer_hand().to_vec(),
+        };
+        sm.try_input(action.clone(), &sign(sk, game_id, &action)).expect("legal action rejected");
+        nonce += 1;
+    }
+    sm
+}
+
+fn sign(sk: &SigningKey, game_id: u64, action: &DeAction) -> Vec<u8> {
+    let domain = action_domain(U256::from(CHAIN_ID), CONTRACT, U256::from(game_id));
+    let signature: Signature =
+        sk.sign_prehash(action_signing_hash(action, &domain).as_slice()).unwrap();
+    signature.to_bytes().to_vec()
+}
+
+/// Transcript of the game as the player would hand it to the guest
+fn transcript(sm: &BlackjackStateMachine, game_id: u64, case: &GameCase) -> GameInput {
+    let mut input = sm.transcript();
+    let (i, signer) = match case.ending {
+        Ending::Complete | Ending::Abandoned => return input,
+        _ if input.actions.is_empty() => return input,
+        Ending::BadNonce(i) => {
+            let i = i % input.actions.len();
+            input.actions[i].nonce = input.actions[i].nonce.wrapping_add(1);
+            (i, SigningKey::from_slice(&[7u8; 32]).unwrap())
+        }
+        Ending::ForeignSignature(i) => {
+            (i % input.actions.len(), SigningKey::from_slice(&[8u8; 32]).unwrap())
+        }
+    };
+    let signature = sign(&signer, game_id, &input.actions[i]);
+    input.signatures[i] =
+        [signature[..32].try_into().unwrap(), signature[32..].try_into().unwrap()];
+    input
+}
+
+/// What the dealer's state machine makes of a transcript
+fn expected(dealer_seed: [u8; 16], input: &GameInput) -> Expected {
+    let replayed =
+        BlackjackStateMachine::from_transcript(dealer_seed, input, CHAIN_ID, CONTRACT).ok();
+    match replayed.filter(|sm| sm.terminated()) {
+        Some(sm) => Expected {
+            payout: sm.winnings(),
+            doubles: sm.doubles().to_vec(),
+            splits: sm.splits().to_vec(),
+            terminated: true,
+            action_hash: B256::ZERO,
+        },
+        None => Expected {
+            payout: U256::ZERO,
+            doubles: Vec::new(),
+            splits: Vec::new(),
+            terminated: false,
+            action_hash: actions_hash(&input.actions).into(),
+        },
+    }
+}
+
+fn check_batch(dealer_seed: [u8; 16], cases: &[GameCase]) -> Result<(), TestCaseError> {
+    let sk = SigningKey::from_slice(&[7u8; 32]).unwrap();
+    let mut expected_results = Vec::new();
+    let mut games = Vec::new();
+    for (game_id, case) in cases.iter().enumerate() {
+        let sm = play(dealer_seed, game_id as u64, case, &sk);
+        let input = transcript(&sm, game_id as u64, case);
+        let expected = expected(dealer_seed, &input);
+        // an untouched transcript replays to what the player was shown
+        if let Ending::Complete | Ending::Abandoned = case.ending {
+            prop_assert_eq!(expected.terminated, sm.terminated());
+            if sm.terminated() {
+                prop_assert_eq!(expected.payout, sm.winnings());
+            }
+        }
+        expected_results.push(expected);
+        games.push(input);
+    }
+
+    let input = Input {
+        dealerSeed: dealer_seed.into(),
+        chainId: U256::from(CHAIN_ID),
+        verifyingContract: CONTRACT,
+        games,
+    };
+    let env = ExecutorEnv::builder().write_slice(&input.abi_encode()).build().unwrap();
+    let session_info = default_executor().execute(env, BLACKJACK_ELF).unwrap();
+    let output = Output::abi_decode(&session_info.journal.bytes, true).unwrap();
+
+    let pubkey = VerifyingKey::from(&sk).to_encoded_point(false).as_bytes().to_vec();
+    for (i, expected) in expected_results.iter().enumerate() {
+        prop_assert_eq!(output.game_ids[i], U256::from(i));
+        prop_assert_eq!(output.player_pubkeys[i].to_vec(), pubkey.clone());
+        prop_assert_eq!(output.terminated[i], expected.terminated, "terminated of game {}", i);
+        prop_assert_eq!(output.payouts[i], expected.payout, "payout of game {}", i);
+        prop_assert_eq!(&output.double_hands[i], &expected.doubles, "doubles of game {}", i);
+        prop_assert_eq!(&output.split_hands[i], &expected.splits, "splits of game {}", i);
+        prop_assert_eq!(output.action_hash[i], expected.action_hash, "action hash of game {}", i);
+    }
+    Ok(())
+}
+
+proptest! {
+    // every case runs the guest in the executor, so keep the number of cases low
+    #![proptest_config(ProptestConfig::with_cases(16))]
+
+    #[test]
+    fn test_state_machine_matches_guest(
+        dealer_seed in any::<[u8; 16]>(),
+        cases in prop::collection::vec(game_case(), 1..=4),
+    ) {
+        check_batch(dealer_seed, &cases)?;
+    }
+}
+
+#[test]
+fn test_unfinished_and_tampered_games_match_guest() {
+    let dealer_seed = [2u8; 16];
+    let sk = SigningKey::from_slice(&[7u8; 32]).unwrap();
+    let cases =
+        [Ending::Abandoned, Ending::BadNonce(0), Ending::ForeignSignature(0)].map(|ending| {
+            GameCase {
+                player_seed: [1u8; 16],
+                bets: vec![100],
+                choices: Vec::new(),
+                ending,
+            }
+        });
+    for (game_id, case) in cases.iter().enumerate() {
+        let sm = play(dealer_seed, game_id as u64, case, &sk);
+        let input = transcript(&sm, game_id as u64, case);
+        assert!(!expected(dealer_seed, &input).terminated, "{:?}", case.ending);
+    }
+    check_batch(dealer_seed, &cases).unwrap();
+}
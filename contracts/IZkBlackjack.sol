@@ -0,0 +1,172 @@
+// SPDX-License-Identifier: Apache-2.0
+pragma solidity ^0.8.20;
+
+/// External surface of `ZkBlackjack`, the dealer's bindings are generated from it.
+/// `ZkBlackjackTest.test_interface_matches_contract` keeps it in sync with the contract.
+interface IZkBlackjack {
+    /// STRUCTS ///
+
+    struct DeAction {
+        uint8 nonce;
+        uint8 handId;
+        uint8 inner;
+        uint8[] my_cards;
+        uint8[] dealer_cards;
+    }
+
+    /// Deserialization of RISC0 journal
+    struct Output {
+        bytes32 dealerCommitment;
+        uint256 chainId;
+        address verifyingContract;
+        uint256[] gameIds;
+        bytes32[] playerCommitments;
+        bytes[] playerPubkeys;
+        uint256[] payouts;
+        uint8[][] doubleHands;
+        uint8[][] splitHands;
+        bytes32[] actionHash;
+        bool[] terminated;
+    }
+
+    /// EVENTS ///
+
+    event GameStarted(
+        address indexed player,
+        uint256 indexed gameId,
+        address indexed dealer,
+        bytes32 dealerCommitment,
+        bytes32 playerCommitment,
+        bytes playerPublicKey,
+        uint256[] bets
+    );
+    event GameResult(
+        address indexed player,
+        uint256 indexed gameId,
+        address indexed dealer,
+        uint256 playerBet,
+        uint256 playerWin
+    );
+    event HandDoubled(uint256 indexed gameId, uint8 handIndex, uint256 amount);
+    event HandSplit(uint256 indexed gameId, uint8 handIndex, uint256 amount);
+    event PlayerActionsRequested(uint256 indexed gameId);
+    event PlayerActionsProvided(uint256 indexed gameId, bytes32 actionsHash);
+
+    /// ADMIN FUNCTIONS ///
+
+    function setDealerWhitelist(address _dealer, bool _able) external;
+
+    function banDealer(address _dealer) external;
+
+    function unbanDealer(address _dealer) external;
+
+    /// PLAYER FUNCTIONS ///
+
+    function startGame(
+        address _dealer,
+        uint256[] calldata _initBets,
+        bytes32 _playerCommitment,
+        bytes calldata _playerPublicKey
+    ) external payable;
+
+    function double(uint256 _gameId, uint8 _handIndex) external payable;
+
+    function split(uint256 _gameId, uint8 _handIndex) external payable;
+
+    function reclaimGame(uint256 _gameId) external;
+
+    function provideActions(
+        uint256 _gameId,
+        DeAction[] calldata _actions
+    ) external;
+
+    /// DEALER FUNCTIONS ///
+
+    function registerDealer(
+        uint256 _minBet,
+        uint256 _maxBet,
+        uint256 _fee,
+        uint256 _timeoutBlocks,
+        bytes32 _commitment
+    ) external;
+
+    function updateCommitment(bytes32 _commitment) external;
+
+    function setFee(uint256 _fee) external;
+
+    function setBetLimits(uint256 _minBet, uint256 _maxBet) external;
+
+    function goOnline() external;
+
+    function goOffline() external;
+
+    function withdraw(uint256 _amount) external;
+
+    function deposit() external payable;
+
+    function transferWinningsToUser(uint256 _gameId, uint256 _payout) external;
+
+    function proveGames(
+        uint256[] calldata _gameIds,
+        Output calldata _output,
+        bytes memory _seal
+    ) external;
+
+    function requestPlayerActions(uint256 _gameId) external;
+
+    /// VIEWS ///
+
+    function verifier() external view returns (address);
+
+    function imageId() external view returns (bytes32);
+
+    function registerAuthority() external view returns (address);
+
+    function games(
+        uint256
+    )
+        external
+        view
+        returns (
+            bool finished,
+            address dealer,
+            address player,
+            bytes32 dealerCommitment,
+            bytes32 playerCommitment,
+            bytes memory playerPublicKey,
+            uint256 playerWin,
+            uint256 gameStartBlock,
+            uint256 playerActionsRequestedBlockNumber,
+            bytes32 playerActionsHash
+        );
+
+    function newGameId() external view returns (uint256);
+
+    function onlineGames(uint256) external view returns (uint256);
+
+    function dealers(
+        address
+    )
+        external
+        view
+        returns (
+            address addr,
+            bool online,
+            uint256 balance,
+            uint256 lockedBalance,
+            bytes32 commitment,
+            uint256 minBet,
+            uint256 maxBet,
+            uint256 fee,
+            bool banned,
+            uint256 timeoutBlocks
+        );
+
+    function onlineDealers(uint256) external view returns (address);
+
+    function dealerWhitelist(address) external view returns (bool);
+
+    function totalBets(uint256 gameId) external view returns (uint256);
+
+    function gameBets(uint256 gameId) external view returns (uint256[] memory);
+}
@@ -66,6 +66,10 @@ contract ZkBlackjack {
     /// Deserialization of RISC0 journal
     struct Output {
         bytes32 dealerCommitment;
+        /// Domain the player's action signatures were verified against
+        uint256 chainId;
+        address verifyingContract;
+        uint256[] gameIds;
         bytes32[] playerCommitments;
         bytes[] playerPubkeys;
         uint256[] payouts;
@@ -80,7 +84,11 @@ contract ZkBlackjack {
     event GameStarted(
         address indexed player,
         uint256 indexed gameId,
-        address indexed dealer
+        address indexed dealer,
+        bytes32 dealerCommitment,
+        bytes32 playerCommitment,
+        bytes playerPublicKey,
+        uint256[] bets
     );
     event GameResult(
         address indexed player,
@@ -89,6 +97,10 @@ contract ZkBlackjack {
         uint256 playerBet,
         uint256 playerWin
     );
+    event HandDoubled(uint256 indexed gameId, uint8 handIndex, uint256 amount);
+    event HandSplit(uint256 indexed gameId, uint8 handIndex, uint256 amount);
+    event PlayerActionsRequested(uint256 indexed gameId);
+    event PlayerActionsProvided(uint256 indexed gameId, bytes32 actionsHash);
 
     /// MODIFIERS ///
 
@@ -207,7 +219,7 @@ contract ZkBlackjack {
 
         _lockBalance(_dealer, totalBet);
 
-        emit GameStarted(msg.sender, newGameId, _dealer);
+        _emitGameStarted(newGameId);
         newGameId++;
     }
 
@@ -228,6 +240,7 @@ contract ZkBlackjack {
         game.bets[_handIndex] += msg.value;
         game.doubleHands.push(_handIndex);
         _lockBalance(game.dealer, msg.value);
+        emit HandDoubled(_gameId, _handIndex, msg.value);
     }
 
     /// Player call this function to split the hand
@@ -251,6 +264,7 @@ contract ZkBlackjack {
         game.bets[_handIndex + 1] = msg.value;
         game.splitHands.push(_handIndex);
         _lockBalance(game.dealer, msg.value);
+        emit HandSplit(_gameId, _handIndex, msg.value);
     }
 
     /// DEALER FUNCTIONS ///
@@ -347,7 +361,7 @@ contract ZkBlackjack {
         require(!game.finished, "game already finished");
         require(game.playerWin == 0, "winnings already transferred");
         require(
-            _payout >=
+            _payout <=
                 dealers[msg.sender].balance - dealers[msg.sender].lockedBalance,
             "dealer is broke"
         );
@@ -377,6 +391,8 @@ contract ZkBlackjack {
                 // Transfer the winnings to the player
                 uint256 payout = _output.payouts[i] - game.playerWin;
                 game.playerWin = _output.payouts[i];
+                // whatever was prepaid with transferWinningsToUser is already off the balance
+                dealers[game.dealer].balance -= payout;
                 _unlockBalance(_gameIds[i]);
                 game.player.transfer(payout);
                 emit GameResult(
@@ -404,8 +420,8 @@ contract ZkBlackjack {
         require(!game.finished, "game already finished");
         require(game.player == msg.sender, "not a player");
         require(
-            game.playerActionsRequestedBlockNumber > 0 &&
-                game.playerActionsHash == 0,
+            game.playerActionsRequestedBlockNumber == 0 ||
+                game.playerActionsHash != 0,
             "player actions were requested and not provided"
         );
         require(
@@ -444,6 +460,7 @@ contract ZkBlackjack {
             "timeout not reached"
         );
         game.playerActionsRequestedBlockNumber = block.number;
+        emit PlayerActionsRequested(_gameId);
     }
 
     function provideActions(
@@ -484,6 +501,7 @@ contract ZkBlackjack {
         }
         bytes32 actionsHash = sha256(abi.encode(_actions));
         game.playerActionsHash = actionsHash;
+        emit PlayerActionsProvided(_gameId, actionsHash);
     }
 
     function _unlockBalance(uint256 _gameId) internal {
@@ -499,6 +517,21 @@ contract ZkBlackjack {
         );
     }
 
+    /// Emits everything the dealer needs to deal the game, so it never has to read old state
+    /// Separate from startGame to keep its stack shallow
+    function _emitGameStarted(uint256 _gameId) internal {
+        Game storage game = games[_gameId];
+        emit GameStarted(
+            game.player,
+            _gameId,
+            game.dealer,
+            game.dealerCommitment,
+            game.playerCommitment,
+            game.playerPublicKey,
+            game.bets
+        );
+    }
+
     function totalBets(uint256 gameId) public view returns (uint256) {
         uint256 totalBet = 0;
         Game storage game = games[gameId];
@@ -508,6 +541,11 @@ contract ZkBlackjack {
         return totalBet;
     }
 
+    /// Current bet of every hand, the public `games` getter leaves out arrays
+    function gameBets(uint256 gameId) public view returns (uint256[] memory) {
+        return games[gameId].bets;
+    }
+
     function verifyProof(
         uint256[] calldata _gameIds,
         Output calldata _output,
@@ -518,9 +556,22 @@ contract ZkBlackjack {
         /// RISC0 proof verification///
         ///////////////////////////////
         verifier.verify(_seal, imageId, sha256(journal));
+        require(_output.chainId == block.chainid, "invalid proof chain id");
+        require(
+            _output.verifyingContract == address(this),
+            "invalid proof contract"
+        );
+        require(
+            _output.gameIds.length == _gameIds.length,
+            "invalid proof game ids"
+        );
         for (uint256 i = 0; i < _gameIds.length; i++) {
             Game storage game = games[_gameIds[i]];
             require(!game.finished, "game already finished");
+            require(
+                _output.gameIds[i] == _gameIds[i],
+                "invalid proof game id"
+            );
             require(
                 keccak256(_output.playerPubkeys[i]) ==
                     keccak256(game.playerPublicKey),
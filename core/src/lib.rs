@@ -0,0 +1,176 @@
+//! Blackjack rules and ABI types shared by the zkVM guest and the dealer server
+
+#![no_std]
+
+extern crate alloc;
+
+mod rules;
+
+use alloc::vec::Vec;
+
+use alloy_primitives::{Address, B256, U256};
+use alloy_sol_types::{sol, Eip712Domain, SolStruct, SolValue};
+
+pub use rules::{game_seed, hand_value, is_blackjack, play, Blackjack, BlackjackState};
+
+use sha2::{Digest, Sha256};
+
+sol!(
+    struct Input {
+        bytes16 dealerSeed;
+        uint256 chainId;
+        address verifyingContract;
+        GameInput[] games;
+    }
+);
+
+sol!(
+    struct GameInput {
+        uint256 gameId;
+        bytes16 playerSeed;
+        bytes pubkey;
+        uint8 initialHands;
+        uint256[] bets;
+        DeAction[] actions;
+        bytes32[2][] signatures;
+    }
+);
+
+sol!(
+    /// Action signed by the player. `dealer_cards` are the cards the player was shown, which is
+    /// only the dealer's upcard while any hand is still being played.
+    #[derive(Debug, PartialEq)]
+    struct DeAction {
+        uint8 nonce;
+        uint8 handId;
+        uint8 inner;
+        uint8[] my_cards;
+        uint8[] dealer_cards;
+    }
+);
+
+sol!(
+    /// Journal of the guest, which is passed to the contract as is
+    struct Output {
+        bytes32 dealer_commitment;
+        uint256 chain_id;
+        address verifying_contract;
+        uint256[] game_ids;
+        bytes32[] player_commitments;
+        bytes[] player_pubkeys;
+        uint256[] payouts;
+        uint8[][] double_hands;
+        uint8[][] split_hands;
+        bytes32[] action_hash;
+        bool[] terminated;
+    }
+);
+
+/// Commitment to a seed, as stored on chain by `startGame` and `registerDealer`
+pub fn commitment(seed: &[u8; 16]) -> [u8; 32] {
+    Sha256::digest(seed).into()
+}
+
+/// Hash the contract stores for the actions of `provideActions`, which the guest reports for
+/// games that didn't terminate
+pub fn actions_hash(actions: &[DeAction]) -> [u8; 32] {
+    Sha256::digest(actions.abi_encode()).into()
+}
+
+/// EIP-712 domain of the player's signatures, so that actions of one game can't be replayed into
+/// another game, contract or chain
+pub fn action_domain(chain_id: U256, contract: Address, game_id: U256) -> Eip712Domain {
+    Eip712Domain::new(
+        Some("ZkBlackjack".into()),
+        Some("1".into()),
+        Some(chain_id),
+        Some(contract),
+        Some(B256::from(game_id)),
+    )
+}
+
+/// Digest the player signs for an action
+pub fn action_signing_hash(action: &DeAction, domain: &Eip712Domain) -> B256 {
+    action.eip712_signing_hash(domain)
+}
+
+pub struct Action {
+    pub hand_id: u8,
+    pub inner: ActionType,
+    pub my_cards: Vec<u8>,
+    pub dealer_cards: Vec<u8>,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ActionType {
+    Hit,
+    Stand,
+    Double,
+    Split,
+}
+
+/// Reasons an action is rejected by the rules
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Error {
+    /// All hands are already resolved
+    GameTerminated,
+    /// Action is for a hand other than the one currently being played
+    UnexpectedHand,
+    /// Cards in the action don't match the current state of the table
+    InvalidCards,
+    /// Action is not allowed for the hand (e.g. splitting non-pairs) or is unknown
+    InvalidAction,
+}
+
+impl TryFrom<u8> for ActionType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Error> {
+        match value {
+            0 => Ok(ActionType::Hit),
+            1 => Ok(ActionType::Stand),
+            2 => Ok(ActionType::Double),
+            3 => Ok(ActionType::Split),
+            _ => Err(Error::InvalidAction),
+        }
+    }
+}
+
+impl From<ActionType> for u8 {
+    fn from(value: ActionType) -> Self {
+        match value {
+            ActionType::Hit => 0,
+            ActionType::Stand => 1,
+            ActionType::Double => 2,
+            ActionType::Split => 3,
+        }
+    }
+}
+
+impl TryFrom<&DeAction> for Action {
+    type Error = Error;
+
+    fn try_from(v: &DeAction) -> Result<Action, Error> {
+        Ok(Action {
+            hand_id: v.handId,
+            inner: v.inner.try_into()?,
+            my_cards: v.my_cards.clone(),
+            dealer_cards: v.dealer_cards.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commitment_is_sha256() {
+        assert_eq!(
+            commitment(&[0u8; 16]),
+            alloy_primitives::hex!(
+                "374708fff7719dd5979ec875d56cd2286f6d3cf7ec317a3b25632aab28ec37bb"
+            )
+        );
+    }
+}
@@ -0,0 +1,455 @@
+//! Dealing, action validation and payouts
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use alloy_primitives::U256;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use crate::{Action, ActionType, Error};
+
+/// Maximum number of hands a player can have after splitting
+const MAX_HANDS: usize = 4;
+
+/// A single round of blackjack, dealt from the combined dealer and player seeds.
+///
+/// Hands are played in order. Bets are tracked per hand the same way the contract does it:
+/// doubling doubles the bet of the hand, splitting inserts a copy of the bet right after it.
+#[derive(Clone)]
+pub struct Blackjack {
+    rng: ChaCha8Rng,
+    dealer_hand: Vec<u8>,
+    player_hands: Vec<Vec<u8>>,
+    hands_active: Vec<bool>,
+    bets: Vec<U256>,
+    doubles: Vec<u8>,
+    splits: Vec<u8>,
+    // zeroed out until the round is resolved
+    winnings: Vec<U256>,
+    hand_id: usize,
+}
+
+/// Everything a `Blackjack` holds besides its seed, to resume a game at the same position in the
+/// deck with `Blackjack::from_state`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlackjackState {
+    /// Position of the deck's RNG, in 32-bit words
+    pub word_pos: u128,
+    pub dealer_hand: Vec<u8>,
+    pub player_hands: Vec<Vec<u8>>,
+    pub hands_active: Vec<bool>,
+    pub bets: Vec<U256>,
+    pub doubles: Vec<u8>,
+    pub splits: Vec<u8>,
+    pub winnings: Vec<U256>,
+    pub hand_id: usize,
+}
+
+impl Blackjack {
+    pub fn new(seed: [u8; 32], bets: Vec<U256>) -> Self {
+        let hands = bets.len();
+        let mut rng = ChaCha8Rng::from_seed(seed);
+        let dealer_hand = vec![get_card(&mut rng), get_card(&mut rng)];
+        let player_hands =
+            (0..hands).map(|_| vec![get_card(&mut rng), get_card(&mut rng)]).collect::<Vec<_>>();
+        let hands_active = player_hands.iter().map(|hand| !is_blackjack(hand)).collect();
+
+        let mut game = Self {
+            rng,
+            dealer_hand,
+            player_hands,
+            hands_active,
+            bets,
+            doubles: Vec::new(),
+            splits: Vec::new(),
+            winnings: vec![U256::ZERO; hands],
+            hand_id: 0,
+        };
+
+        // dealer's blackjack ends the round right away
+        if is_blackjack(&game.dealer_hand) {
+            game.hands_active.iter_mut().for_each(|active| *active = false);
+        }
+        if game.terminated() {
+            game.resolve();
+        }
+        game
+    }
+
+    /// Resumes a game from `state`, which must come from `Blackjack::state` of a game dealt with
+    /// the same seed. Returns `None` if the hands, bets and winnings don't line up, or if the
+    /// game is still going and `hand_id` isn't at the next hand to play.
+    pub fn from_state(seed: [u8; 32], state: BlackjackState) -> Option<Self> {
+        let hands = state.player_hands.len();
+        if state.dealer_hand.len() < 2
+            || state.hands_active.len() != hands
+            || state.bets.len() != hands
+            || state.winnings.len() != hands
+            || state.hand_id > hands
+        {
+            return None;
+        }
+        // hands before `hand_id` are done, and `act` skips inactive hands from `hand_id` on until
+        // it finds one to play. Blackjacks are inactive from the deal, so `hand_id` may point at
+        // an inactive hand while a later one is active.
+        let (played, remaining) = state.hands_active.split_at(state.hand_id);
+        let terminated = !state.hands_active.contains(&true);
+        if played.contains(&true) || !(terminated || remaining.contains(&true)) {
+            return None;
+        }
+        let mut rng = ChaCha8Rng::from_seed(seed);
+        rng.set_word_pos(state.word_pos);
+        Some(Self {
+            rng,
+            dealer_hand: state.dealer_hand,
+            player_hands: state.player_hands,
+            hands_active: state.hands_active,
+            bets: state.bets,
+            doubles: state.doubles,
+            splits: state.splits,
+            winnings: state.winnings,
+            hand_id: state.hand_id,
+        })
+    }
+
+    pub fn state(&self) -> BlackjackState {
+        BlackjackState {
+            word_pos: self.rng.get_word_pos(),
+            dealer_hand: self.dealer_hand.clone(),
+            player_hands: self.player_hands.clone(),
+            hands_active: self.hands_active.clone(),
+            bets: self.bets.clone(),
+            doubles: self.doubles.clone(),
+            splits: self.splits.clone(),
+            winnings: self.winnings.clone(),
+            hand_id: self.hand_id,
+        }
+    }
+
+    /// Applies the player's action to the hand currently being played
+    pub fn act(&mut self, action: &Action) -> Result<(), Error> {
+        if self.terminated() {
+            return Err(Error::GameTerminated);
+        }
+        // skip hands that are not active
+        while !self.hands_active[self.hand_id] {
+            self.hand_id += 1;
+        }
+        if action.hand_id as usize != self.hand_id {
+            return Err(Error::UnexpectedHand);
+        }
+        let hand_id = self.hand_id;
+        if self.player_hands[hand_id] != action.my_cards
+            || self.visible_dealer_hand() != action.dealer_cards
+        {
+            return Err(Error::InvalidCards);
+        }
+
+        match action.inner {
+            ActionType::Hit => {
+                if self.player_hands[hand_id].iter().sum::<u8>() > 21 {
+                    return Err(Error::InvalidAction);
+                }
+                self.player_hands[hand_id].push(get_card(&mut self.rng));
+                if self.player_hands[hand_id].iter().sum::<u8>() > 21 {
+                    self.finish_hand();
+                }
+            }
+            ActionType::Stand => {
+                self.finish_hand();
+            }
+            ActionType::Double => {
+                if self.player_hands[hand_id].len() != 2 {
+                    return Err(Error::InvalidAction);
+                }
+                self.player_hands[hand_id].push(get_card(&mut self.rng));
+                self.bets[hand_id] = self.bets[hand_id].checked_mul(U256::from(2)).unwrap();
+                self.doubles.push(hand_id as u8);
+                self.finish_hand();
+            }
+            ActionType::Split => {
+                if self.player_hands[hand_id].len() != 2 || self.player_hands.len() == MAX_HANDS {
+                    return Err(Error::InvalidAction);
+                }
+                // can only split if both cards are the same
+                if self.player_hands[hand_id][0] != self.player_hands[hand_id][1] {
+                    return Err(Error::InvalidAction);
+                }
+                let card = self.player_hands[hand_id].pop().unwrap();
+                self.player_hands.insert(hand_id + 1, vec![card]);
+                self.hands_active.insert(hand_id + 1, true);
+                self.bets.insert(hand_id + 1, self.bets[hand_id]);
+                self.winnings.insert(hand_id + 1, U256::ZERO);
+                self.player_hands[hand_id].push(get_card(&mut self.rng));
+                self.splits.push(hand_id as u8);
+            }
+        }
+
+        if self.terminated() {
+            self.resolve();
+        }
+        Ok(())
+    }
+
+    fn finish_hand(&mut self) {
+        self.hands_active[self.hand_id] = false;
+        self.hand_id += 1;
+    }
+
+    /// Draws the dealer's cards (standing on all 17s) and pays out every hand
+    fn resolve(&mut self) {
+        let dealer_blackjack = is_blackjack(&self.dealer_hand);
+        while hand_value(&self.dealer_hand) < 17 {
+            self.dealer_hand.push(get_card(&mut self.rng));
+        }
+        let dealer_sum = hand_value(&self.dealer_hand);
+
+        for (id, hand) in self.player_hands.iter().enumerate() {
+            let bet = self.bets[id];
+            let hand_sum = hand_value(hand);
+            self.winnings[id] = if is_blackjack(hand) {
+                if dealer_blackjack {
+                    bet
+                } else {
+                    bet.checked_mul(U256::from(5)).unwrap().checked_div(U256::from(2)).unwrap()
+                }
+            } else if dealer_blackjack || hand_sum > 21 {
+                U256::ZERO
+            } else if dealer_sum > 21 || hand_sum > dealer_sum {
+                bet.checked_mul(U256::from(2)).unwrap()
+            } else if hand_sum == dealer_sum {
+                bet
+            } else {
+                U256::ZERO
+            };
+        }
+    }
+
+    pub fn terminated(&self) -> bool {
+        self.hands_active.iter().all(|&active| !active)
+    }
+
+    pub fn dealer_hand(&self) -> &[u8] {
+        &self.dealer_hand
+    }
+
+    /// Dealer's cards as shown to the player: only the upcard until every hand is resolved
+    pub fn visible_dealer_hand(&self) -> &[u8] {
+        if self.terminated() {
+            &self.dealer_hand
+        } else {
+            &self.dealer_hand[..1]
+        }
+    }
+
+    pub fn player_hands(&self) -> &[Vec<u8>] {
+        &self.player_hands
+    }
+
+    pub fn hands_active(&self) -> &[bool] {
+        &self.hands_active
+    }
+
+    /// Current bet of every hand, including doubles and splits
+    pub fn bets(&self) -> &[U256] {
+        &self.bets
+    }
+
+    /// Hands that were doubled, in the order of the actions
+    pub fn doubles(&self) -> &[u8] {
+        &self.doubles
+    }
+
+    /// Hands that were split, in the order of the actions
+    pub fn splits(&self) -> &[u8] {
+        &self.splits
+    }
+
+    /// Payout of every hand, including the returned bet
+    pub fn winnings(&self) -> &[U256] {
+        &self.winnings
+    }
+
+    /// Total payout of the round, including the returned bets. Zero while hands are still active.
+    pub fn payout(&self) -> U256 {
+        self.winnings.iter().fold(U256::ZERO, |acc, x| acc.checked_add(*x).unwrap())
+    }
+}
+
+/// Plays out a whole transcript of actions
+pub fn play(seed: [u8; 32], bets: Vec<U256>, actions: &[Action]) -> Result<Blackjack, Error> {
+    let mut game = Blackjack::new(seed, bets);
+    for action in actions {
+        game.act(action)?;
+    }
+    Ok(game)
+}
+
+/// Seed of the game's deck, derived from both parties' seeds
+pub fn game_seed(dealer_seed: [u8; 16], player_seed: [u8; 16]) -> [u8; 32] {
+    let mut seed = [0u8; 32];
+    seed[..16].copy_from_slice(&dealer_seed);
+    seed[16..].copy_from_slice(&player_seed);
+    seed
+}
+
+/// Best value of the hand, counting an ace as 11 if it doesn't bust
+pub fn hand_value(hand: &[u8]) -> u8 {
+    let sum = hand.iter().sum::<u8>();
+    if hand.contains(&1) && sum + 10 <= 21 {
+        sum + 10
+    } else {
+        sum
+    }
+}
+
+pub fn is_blackjack(hand: &[u8]) -> bool {
+    hand.len() == 2 && hand.contains(&1) && hand.contains(&10)
+}
+
+fn get_card(rng: &mut ChaCha8Rng) -> u8 {
+    let card: u8 = rng.gen::<u8>() % 13 + 1;
+    if card > 10 {
+        10
+    } else {
+        card
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Finds a deal where the dealer has no blackjack and the first hand satisfies `pred`
+    fn find_game(bets: Vec<U256>, pred: impl Fn(&[u8]) -> bool) -> Blackjack {
+        (0u8..=255)
+            .map(|i| Blackjack::new([i; 32], bets.clone()))
+            .find(|game| !game.terminated() && pred(&game.player_hands()[0]))
+            .expect("no matching deal")
+    }
+
+    fn action(game: &Blackjack, inner: ActionType) -> Action {
+        let hand_id = game.hands_active().iter().position(|&active| active).unwrap();
+        Action {
+            hand_id: hand_id as u8,
+            inner,
+            my_cards: game.player_hands()[hand_id].clone(),
+            dealer_cards: game.visible_dealer_hand().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_hand_value() {
+        assert_eq!(hand_value(&[1, 10]), 21);
+        assert_eq!(hand_value(&[1, 1, 9]), 21);
+        assert_eq!(hand_value(&[1, 5, 10]), 16);
+        assert_eq!(hand_value(&[10, 10, 5]), 25);
+    }
+
+    #[test]
+    fn test_split_pays_every_hand() {
+        let mut game = find_game(vec![U256::from(100)], |hand| hand[0] == hand[1]);
+        game.act(&action(&game, ActionType::Split)).unwrap();
+        assert_eq!(game.player_hands().len(), 2);
+        assert_eq!(game.bets(), &[U256::from(100), U256::from(100)]);
+        assert_eq!(game.hands_active(), &[true, true]);
+
+        while !game.terminated() {
+            game.act(&action(&game, ActionType::Stand)).unwrap();
+        }
+        assert_eq!(game.splits(), &[0]);
+        assert_eq!(game.winnings().len(), 2);
+        assert_eq!(game.payout(), game.winnings()[0] + game.winnings()[1]);
+    }
+
+    #[test]
+    fn test_double_after_split() {
+        let mut game = find_game(vec![U256::from(100), U256::from(50)], |hand| hand[0] == hand[1]);
+        game.act(&action(&game, ActionType::Split)).unwrap();
+        game.act(&action(&game, ActionType::Double)).unwrap();
+        assert_eq!(game.bets(), &[U256::from(200), U256::from(100), U256::from(50)]);
+        assert_eq!(game.doubles(), &[0]);
+        assert_eq!(game.hands_active()[..2], [false, true]);
+    }
+
+    #[test]
+    fn test_double_doubles_bet() {
+        let mut game = find_game(vec![U256::from(100)], |hand| hand.len() == 2);
+        game.act(&action(&game, ActionType::Double)).unwrap();
+        assert!(game.terminated());
+        assert_eq!(game.bets(), &[U256::from(200)]);
+        assert_eq!(game.doubles(), &[0]);
+        assert!([U256::ZERO, U256::from(200), U256::from(400)].contains(&game.payout()));
+    }
+
+    #[test]
+    fn test_resumed_game_deals_the_same_cards() {
+        let seed = [3; 32];
+        let mut game = Blackjack::new(seed, vec![U256::from(100), U256::from(50)]);
+        let mut resumed = Blackjack::from_state(seed, game.state()).unwrap();
+        while !game.terminated() {
+            let hit = action(&game, ActionType::Hit);
+            game.act(&hit).unwrap();
+            resumed.act(&hit).unwrap();
+            assert_eq!(resumed.state(), game.state());
+        }
+        assert_eq!(resumed.dealer_hand(), game.dealer_hand());
+
+        let mut state = game.state();
+        state.bets.pop();
+        assert!(Blackjack::from_state(seed, state).is_none());
+    }
+
+    #[test]
+    fn test_rejects_states_off_the_hand_being_played() {
+        let bets = vec![U256::from(100), U256::from(50)];
+        // the first hand is a blackjack, play starts at the second one
+        let seed = (0u8..=255)
+            .map(|i| [i; 32])
+            .find(|&seed| Blackjack::new(seed, bets.clone()).hands_active() == [false, true])
+            .expect("no matching deal");
+        let mut game = Blackjack::new(seed, bets);
+        assert!(Blackjack::from_state(seed, game.state()).is_some());
+
+        // `act` would run past the last hand
+        let mut past_the_end = game.state();
+        past_the_end.hand_id = 2;
+        assert!(Blackjack::from_state(seed, past_the_end).is_none());
+        // an earlier hand is still waiting to be played
+        let mut skipped = game.state();
+        skipped.hands_active[0] = true;
+        skipped.hand_id = 1;
+        assert!(Blackjack::from_state(seed, skipped).is_none());
+
+        let stand = action(&game, ActionType::Stand);
+        game.act(&stand).unwrap();
+        assert!(game.terminated());
+        let mut resumed = Blackjack::from_state(seed, game.state()).unwrap();
+        assert_eq!(resumed.act(&stand), Err(Error::GameTerminated));
+    }
+
+    #[test]
+    fn test_rejects_stale_cards() {
+        let mut game = find_game(vec![U256::from(100)], |_| true);
+        let mut stale = action(&game, ActionType::Hit);
+        stale.dealer_cards.push(1);
+        assert_eq!(game.act(&stale), Err(Error::InvalidCards));
+    }
+
+    #[test]
+    fn test_hole_card_hidden_until_resolved() {
+        let mut game = find_game(vec![U256::from(100)], |_| true);
+        assert_eq!(game.visible_dealer_hand(), &game.dealer_hand()[..1]);
+
+        // signing the hole card is rejected, it was never shown
+        let mut peeked = action(&game, ActionType::Stand);
+        peeked.dealer_cards = game.dealer_hand().to_vec();
+        assert_eq!(game.act(&peeked), Err(Error::InvalidCards));
+
+        game.act(&action(&game, ActionType::Stand)).unwrap();
+        assert!(game.terminated());
+        assert_eq!(game.visible_dealer_hand(), game.dealer_hand());
+        assert!(game.dealer_hand().len() >= 2);
+    }
+}
@@ -3,69 +3,33 @@ include!(concat!(env!("OUT_DIR"), "/methods.rs"));
 
 #[cfg(test)]
 mod tests {
-    use alloy_primitives::U256;
-    use alloy_sol_types::{sol, SolValue};
-    use k256::ecdsa::signature::SignerMut;
+    use alloy_primitives::{Address, B256, U256};
+    use alloy_sol_types::SolValue;
+    use blackjack_core::{
+        action_domain, action_signing_hash, actions_hash, game_seed, hand_value, is_blackjack,
+        ActionType, Blackjack, DeAction, GameInput, Input, Output,
+    };
+    use k256::ecdsa::signature::hazmat::PrehashSigner;
     use k256::ecdsa::{Signature, SigningKey, VerifyingKey};
     use risc0_zkvm::{default_executor, ExecutorEnv};
 
-    sol!(
-        struct Input {
-            bytes16 dealer_seed;
-            GameInput[] games;
-        }
-    );
-
-    sol!(
-        struct GameInput {
-            bytes16 player_seed;
-            bytes pubkey;
-            uint8 initial_hands;
-            uint256[] bets;
-            DeAction[] actions;
-            bytes32[2][] signatures;
-        }
-    );
-
-    sol!(
-        struct DeAction {
-            uint8 nonce;
-            uint8 hand_id;
-            uint8 inner;
-            uint8[] my_cards;
-            uint8[] dealer_cards;
-        }
-    );
-
-    sol!(
-        struct Output {
-            bytes32 dealer_commitment;
-            bytes32[] player_commitments;
-            bytes[] player_pubkeys;
-            uint256[] payouts;
-            uint8[][] double_hands;
-            uint8[][] split_hands;
-            bytes32[] action_hash;
-            bool[] terminated;
-        }
-    );
-
     #[test]
     fn test_correct_game() {
         let sk = SigningKey::random(&mut rand::thread_rng());
         let vk = VerifyingKey::from(&sk).to_encoded_point(false).to_bytes();
         let action = DeAction {
             nonce: 0,
-            hand_id: 0,
+            handId: 0,
             inner: 2,
             my_cards: vec![2, 9],
-            dealer_cards: vec![10, 4],
+            dealer_cards: vec![10],
         };
         let signature = sign_action(&action, sk);
         let game = GameInput {
-            player_seed: [1u8; 16].into(),
+            gameId: U256::ZERO,
+            playerSeed: [1u8; 16].into(),
             pubkey: vk.clone().into(),
-            initial_hands: 1,
+            initialHands: 1,
             bets: vec![U256::from(100)],
             actions: vec![action],
             signatures: vec![[
@@ -74,7 +38,9 @@ mod tests {
             ]],
         };
         let inputs = Input {
-            dealer_seed: [0u8; 16].into(),
+            dealerSeed: [0u8; 16].into(),
+            chainId: U256::from(CHAIN_ID),
+            verifyingContract: CONTRACT,
             games: vec![game],
         };
 
@@ -84,11 +50,179 @@ mod tests {
 
         let x = Output::abi_decode(&session_info.journal.bytes, true).unwrap();
         assert_eq!(x.payouts[0], U256::from(0));
-        assert_eq!(x.terminated[0], true);
+        assert!(x.terminated[0]);
+    }
+
+    #[test]
+    fn test_split_game_pays_every_hand() {
+        let sk = SigningKey::random(&mut rand::thread_rng());
+        let vk = VerifyingKey::from(&sk).to_encoded_point(false).to_bytes();
+        let dealer_seed = [0u8; 16];
+        let bets = vec![U256::from(100)];
+
+        // find a deal with a pair in the first hand
+        let (player_seed, mut game) = (0u8..=255)
+            .map(|i| ([i; 16], Blackjack::new(game_seed(dealer_seed, [i; 16]), bets.clone())))
+            .find(|(_, game)| {
+                let hand = &game.player_hands()[0];
+                !game.terminated() && hand[0] == hand[1]
+            })
+            .unwrap();
+
+        // split, double the first hand and stand on the second one
+        let mut actions = Vec::new();
+        for inner in [ActionType::Split, ActionType::Double, ActionType::Stand] {
+            let hand_id = game.hands_active().iter().position(|&active| active).unwrap();
+            let action = DeAction {
+                nonce: actions.len() as u8,
+                handId: hand_id as u8,
+                inner: inner.into(),
+                my_cards: game.player_hands()[hand_id].clone(),
+                dealer_cards: game.visible_dealer_hand().to_vec(),
+            };
+            game.act(&(&action).try_into().unwrap()).unwrap();
+            actions.push(action);
+        }
+        assert!(game.terminated());
+
+        // the contract's bookkeeping: split copies the bet, double adds it again
+        let hand_bets = [U256::from(200), U256::from(100)];
+        let dealer_sum = hand_value(game.dealer_hand());
+        let expected =
+            game.player_hands().iter().zip(hand_bets).fold(U256::ZERO, |acc, (hand, bet)| {
+                let hand_sum = hand_value(hand);
+                acc + if is_blackjack(hand) {
+                    bet * U256::from(5) / U256::from(2)
+                } else if hand_sum > 21 {
+                    U256::ZERO
+                } else if dealer_sum > 21 || hand_sum > dealer_sum {
+                    bet * U256::from(2)
+                } else if hand_sum == dealer_sum {
+                    bet
+                } else {
+                    U256::ZERO
+                }
+            });
+
+        let signatures = actions
+            .iter()
+            .map(|action| {
+                let signature = sign_action(action, sk.clone());
+                [signature[0..32].try_into().unwrap(), signature[32..64].try_into().unwrap()]
+            })
+            .collect();
+        let inputs = Input {
+            dealerSeed: dealer_seed.into(),
+            chainId: U256::from(CHAIN_ID),
+            verifyingContract: CONTRACT,
+            games: vec![GameInput {
+                gameId: U256::ZERO,
+                playerSeed: player_seed.into(),
+                pubkey: vk.clone().into(),
+                initialHands: 1,
+                bets,
+                actions,
+                signatures,
+            }],
+        };
+
+        let env = ExecutorEnv::builder().write_slice(&inputs.abi_encode()).build().unwrap();
+
+        let session_info = default_executor().execute(env, super::BLACKJACK_ELF).unwrap();
+
+        let x = Output::abi_decode(&session_info.journal.bytes, true).unwrap();
+        assert!(x.terminated[0]);
+        assert_eq!(x.split_hands[0], vec![0]);
+        assert_eq!(x.double_hands[0], vec![0]);
+        assert_eq!(x.payouts[0], expected);
+    }
+
+    #[test]
+    fn test_replayed_signature_is_rejected() {
+        let sk = SigningKey::random(&mut rand::thread_rng());
+        let vk = VerifyingKey::from(&sk).to_encoded_point(false).to_bytes();
+        let action = DeAction {
+            nonce: 0,
+            handId: 0,
+            inner: 2,
+            my_cards: vec![2, 9],
+            dealer_cards: vec![10],
+        };
+        // signed for game 0, submitted as game 1 with the same cards
+        let signature = sign_action(&action, sk);
+        let game = GameInput {
+            gameId: U256::from(1),
+            playerSeed: [1u8; 16].into(),
+            pubkey: vk.clone().into(),
+            initialHands: 1,
+            bets: vec![U256::from(100)],
+            actions: vec![action],
+            signatures: vec![[
+                signature[0..32].try_into().unwrap(),
+                signature[32..64].try_into().unwrap(),
+            ]],
+        };
+        let inputs = Input {
+            dealerSeed: [0u8; 16].into(),
+            chainId: U256::from(CHAIN_ID),
+            verifyingContract: CONTRACT,
+            games: vec![game],
+        };
+
+        let env = ExecutorEnv::builder().write_slice(&inputs.abi_encode()).build().unwrap();
+
+        let session_info = default_executor().execute(env, super::BLACKJACK_ELF).unwrap();
+
+        let x = Output::abi_decode(&session_info.journal.bytes, true).unwrap();
+        assert_eq!(x.game_ids[0], U256::from(1));
+        assert!(!x.terminated[0]);
     }
 
-    fn sign_action(action: &DeAction, mut sk: SigningKey) -> Vec<u8> {
-        let sig: Signature = sk.sign(&action.abi_encode());
+    #[test]
+    fn test_provided_actions_are_hashed() {
+        let sk = SigningKey::random(&mut rand::thread_rng());
+        let vk = VerifyingKey::from(&sk).to_encoded_point(false).to_bytes();
+        // provided by the player on chain, out of order and without a signature
+        let action = DeAction {
+            nonce: 3,
+            handId: 0,
+            inner: 1,
+            my_cards: vec![2, 9],
+            dealer_cards: vec![10],
+        };
+        let game = GameInput {
+            gameId: U256::ZERO,
+            playerSeed: [1u8; 16].into(),
+            pubkey: vk.clone().into(),
+            initialHands: 1,
+            bets: vec![U256::from(100)],
+            actions: vec![action.clone()],
+            signatures: vec![[B256::ZERO; 2]],
+        };
+        let inputs = Input {
+            dealerSeed: [0u8; 16].into(),
+            chainId: U256::from(CHAIN_ID),
+            verifyingContract: CONTRACT,
+            games: vec![game],
+        };
+
+        let env = ExecutorEnv::builder().write_slice(&inputs.abi_encode()).build().unwrap();
+
+        let session_info = default_executor().execute(env, super::BLACKJACK_ELF).unwrap();
+
+        let x = Output::abi_decode(&session_info.journal.bytes, true).unwrap();
+        assert!(!x.terminated[0]);
+        assert_eq!(x.action_hash[0], B256::from(actions_hash(&[action])));
+    }
+
+    const CHAIN_ID: u64 = 11155111;
+    const CONTRACT: Address = Address::repeat_byte(0x42);
+
+    /// Signs the action for game 0
+    fn sign_action(action: &DeAction, sk: SigningKey) -> Vec<u8> {
+        let domain = action_domain(U256::from(CHAIN_ID), CONTRACT, U256::ZERO);
+        let sig: Signature =
+            sk.sign_prehash(action_signing_hash(action, &domain).as_slice()).unwrap();
         sig.to_bytes().to_vec()
     }
 }
@@ -4,8 +4,10 @@ pragma solidity ^0.8.20;
 import {RiscZeroCheats} from "risc0/test/RiscZeroCheats.sol";
 import {console2} from "forge-std/console2.sol";
 import {Test} from "forge-std/Test.sol";
+import {Vm} from "forge-std/Vm.sol";
 import {IRiscZeroVerifier} from "risc0/IRiscZeroVerifier.sol";
 import {ZkBlackjack} from "../contracts/ZkBlackjack.sol";
+import {IZkBlackjack} from "../contracts/IZkBlackjack.sol";
 import {Elf} from "./Elf.sol"; // auto-generated contract after running `cargo build`.
 
 contract ZkBlackjackTest is RiscZeroCheats, Test {
@@ -38,10 +40,13 @@ contract ZkBlackjackTest is RiscZeroCheats, Test {
 
     struct Input {
         bytes16 dealerSeed;
+        uint256 chainId;
+        address verifyingContract;
         GameInput[] games;
     }
 
     struct GameInput {
+        uint256 gameId;
         bytes16 playerSeed;
         bytes pubkey;
         uint8 initialHands;
@@ -67,6 +72,7 @@ contract ZkBlackjackTest is RiscZeroCheats, Test {
         bytes memory pubkey = hex"04b5789617d2b152815256faa4a995d9d08a7ead3deae3e9356d51f6b0ff6caa45c21944b0f47365a2c1d0b4c5237f3e3322dc6ea4cf4a9c41818f692e4e348633";
         GameInput[] memory games = new GameInput[](1);
         GameInput memory gameInput = GameInput({
+            gameId: 0,
             playerSeed: playerSeed,
             pubkey: pubkey,
             initialHands: 1,
@@ -76,7 +82,12 @@ contract ZkBlackjackTest is RiscZeroCheats, Test {
         });
         games[0] = gameInput;
 
-        Input memory input = Input({dealerSeed: dealerSeed, games: games});
+        Input memory input = Input({
+            dealerSeed: dealerSeed,
+            chainId: block.chainid,
+            verifyingContract: address(zkBlackjack),
+            games: games
+        });
 
         (bytes memory journal, bytes memory seal) = prove(
             Elf.BLACKJACK_PATH,
@@ -103,6 +114,64 @@ contract ZkBlackjackTest is RiscZeroCheats, Test {
         // Somehow with these pre-defined seeds, the player got a blackjack.
         // what are the odds of this happening?
         assertEq(player.balance, 2.5 ether);
+        (, , uint256 balance, , , , , , , ) = zkBlackjack.dealers(dealer);
+        assertEq(balance, 98.5 ether);
+    }
+
+    function test_transfer_winnings_before_proof() public {
+        uint256[] memory bets = new uint256[](1);
+        bets[0] = 1 ether;
+        DeAction[] memory actions = new DeAction[](0);
+        bytes32[2][] memory signatures = new bytes32[2][](0);
+
+        bytes memory pubkey = hex"04b5789617d2b152815256faa4a995d9d08a7ead3deae3e9356d51f6b0ff6caa45c21944b0f47365a2c1d0b4c5237f3e3322dc6ea4cf4a9c41818f692e4e348633";
+        GameInput[] memory games = new GameInput[](1);
+        games[0] = GameInput({
+            gameId: 0,
+            playerSeed: playerSeed,
+            pubkey: pubkey,
+            initialHands: 1,
+            bets: bets,
+            actions: actions,
+            signatures: signatures
+        });
+
+        Input memory input = Input({
+            dealerSeed: dealerSeed,
+            chainId: block.chainid,
+            verifyingContract: address(zkBlackjack),
+            games: games
+        });
+
+        (bytes memory journal, bytes memory seal) = prove(
+            Elf.BLACKJACK_PATH,
+            abi.encode(input)
+        );
+
+        uint256[] memory gameIds = new uint256[](1);
+        gameIds[0] = 0;
+        ZkBlackjack.Output memory output = abi.decode(
+            journal,
+            (ZkBlackjack.Output)
+        );
+
+        bytes32 playerCommitment = sha256(abi.encodePacked(playerSeed));
+        vm.startPrank(player);
+        vm.deal(player, 1 ether);
+        zkBlackjack.startGame{value: 1 ether}(dealer, bets, playerCommitment, pubkey);
+
+        // the dealer pays out the blackjack right away
+        vm.startPrank(dealer);
+        zkBlackjack.transferWinningsToUser(0, 2.5 ether);
+        assertEq(player.balance, 2.5 ether);
+
+        // the proof only reconciles, it doesn't pay the player twice
+        zkBlackjack.proveGames(gameIds, output, seal);
+        assertEq(player.balance, 2.5 ether);
+        (, , uint256 balance, uint256 lockedBalance, , , , , , ) = zkBlackjack
+            .dealers(dealer);
+        assertEq(balance, 98.5 ether);
+        assertEq(lockedBalance, 0);
     }
 
     function test_double() public {
@@ -112,19 +181,23 @@ contract ZkBlackjackTest is RiscZeroCheats, Test {
         uint8[] memory myCards = new uint8[](2);
         myCards[0] = 3;
         myCards[1] = 4;
-        uint8[] memory dealerCards = new uint8[](2);
+        // only the dealer's upcard is shown to the player
+        uint8[] memory dealerCards = new uint8[](1);
         dealerCards[0] = 5;
-        dealerCards[1] = 10;
         actions[0] = DeAction(0, 0, 2, myCards, dealerCards);
+
+        Vm.Wallet memory wallet = vm.createWallet("player");
         bytes32[2][] memory signatures = new bytes32[2][](1);
-        signatures[0] = [
-            bytes32(0x3765d536a945e744a208c1f7107adb10ca467fd056cd25809ca1ca18f37e7f3a),
-            bytes32(0x5398c0461fb9e71f3c6f98e4aa02a5b8759ee7451f268bf31371c853fb8d08e9)
-        ];
+        signatures[0] = signAction(wallet, 0, actions[0]);
 
-        bytes memory pubkey = hex"0472b1b25d19128778cdc636a105cdbdf3e0e8435cba8474fac49f7231e0ff798303cd3bb9e88f9704e2aa5f1f2a2d7a2bc73496772839504b0f7995979cad6166";
+        bytes memory pubkey = abi.encodePacked(
+            bytes1(0x04),
+            wallet.publicKeyX,
+            wallet.publicKeyY
+        );
         GameInput[] memory games = new GameInput[](1);
         GameInput memory gameInput = GameInput({
+            gameId: 0,
             playerSeed: playerSeed,
             pubkey: pubkey,
             initialHands: 1,
@@ -134,7 +207,12 @@ contract ZkBlackjackTest is RiscZeroCheats, Test {
         });
         games[0] = gameInput;
 
-        Input memory input = Input({dealerSeed: dealerSeed2, games: games});
+        Input memory input = Input({
+            dealerSeed: dealerSeed2,
+            chainId: block.chainid,
+            verifyingContract: address(zkBlackjack),
+            games: games
+        });
 
         (bytes memory journal, bytes memory seal) = prove(
             Elf.BLACKJACK_PATH,
@@ -168,4 +246,121 @@ contract ZkBlackjackTest is RiscZeroCheats, Test {
         // Idk why I'm so lucky. Prob should go to casino.
         assertEq(player.balance, 2 ether);
     }
+
+    /// The dealer's bindings are generated from IZkBlackjack, it must not drift from the contract
+    function test_interface_matches_contract() public view {
+        bytes4[30] memory interfaceSelectors = [
+            IZkBlackjack.setDealerWhitelist.selector,
+            IZkBlackjack.banDealer.selector,
+            IZkBlackjack.unbanDealer.selector,
+            IZkBlackjack.startGame.selector,
+            IZkBlackjack.double.selector,
+            IZkBlackjack.split.selector,
+            IZkBlackjack.reclaimGame.selector,
+            IZkBlackjack.provideActions.selector,
+            IZkBlackjack.registerDealer.selector,
+            IZkBlackjack.updateCommitment.selector,
+            IZkBlackjack.setFee.selector,
+            IZkBlackjack.setBetLimits.selector,
+            IZkBlackjack.goOnline.selector,
+            IZkBlackjack.goOffline.selector,
+            IZkBlackjack.withdraw.selector,
+            IZkBlackjack.deposit.selector,
+            IZkBlackjack.transferWinningsToUser.selector,
+            IZkBlackjack.proveGames.selector,
+            IZkBlackjack.requestPlayerActions.selector,
+            IZkBlackjack.verifier.selector,
+            IZkBlackjack.imageId.selector,
+            IZkBlackjack.registerAuthority.selector,
+            IZkBlackjack.games.selector,
+            IZkBlackjack.newGameId.selector,
+            IZkBlackjack.onlineGames.selector,
+            IZkBlackjack.dealers.selector,
+            IZkBlackjack.onlineDealers.selector,
+            IZkBlackjack.dealerWhitelist.selector,
+            IZkBlackjack.totalBets.selector,
+            IZkBlackjack.gameBets.selector
+        ];
+        bytes4[30] memory contractSelectors = [
+            zkBlackjack.setDealerWhitelist.selector,
+            zkBlackjack.banDealer.selector,
+            zkBlackjack.unbanDealer.selector,
+            zkBlackjack.startGame.selector,
+            zkBlackjack.double.selector,
+            zkBlackjack.split.selector,
+            zkBlackjack.reclaimGame.selector,
+            zkBlackjack.provideActions.selector,
+            zkBlackjack.registerDealer.selector,
+            zkBlackjack.updateCommitment.selector,
+            zkBlackjack.setFee.selector,
+            zkBlackjack.setBetLimits.selector,
+            zkBlackjack.goOnline.selector,
+            zkBlackjack.goOffline.selector,
+            zkBlackjack.withdraw.selector,
+            zkBlackjack.deposit.selector,
+            zkBlackjack.transferWinningsToUser.selector,
+            zkBlackjack.proveGames.selector,
+            zkBlackjack.requestPlayerActions.selector,
+            zkBlackjack.verifier.selector,
+            zkBlackjack.imageId.selector,
+            zkBlackjack.registerAuthority.selector,
+            zkBlackjack.games.selector,
+            zkBlackjack.newGameId.selector,
+            zkBlackjack.onlineGames.selector,
+            zkBlackjack.dealers.selector,
+            zkBlackjack.onlineDealers.selector,
+            zkBlackjack.dealerWhitelist.selector,
+            zkBlackjack.totalBets.selector,
+            zkBlackjack.gameBets.selector
+        ];
+        for (uint256 i = 0; i < interfaceSelectors.length; i++) {
+            assertEq(
+                bytes32(interfaceSelectors[i]),
+                bytes32(contractSelectors[i])
+            );
+        }
+        assertEq(IZkBlackjack.GameStarted.selector, ZkBlackjack.GameStarted.selector);
+        assertEq(IZkBlackjack.GameResult.selector, ZkBlackjack.GameResult.selector);
+        assertEq(IZkBlackjack.HandDoubled.selector, ZkBlackjack.HandDoubled.selector);
+        assertEq(IZkBlackjack.HandSplit.selector, ZkBlackjack.HandSplit.selector);
+        assertEq(IZkBlackjack.PlayerActionsRequested.selector, ZkBlackjack.PlayerActionsRequested.selector);
+        assertEq(IZkBlackjack.PlayerActionsProvided.selector, ZkBlackjack.PlayerActionsProvided.selector);
+    }
+
+    /// Signs the EIP-712 digest of the action, bound to this chain, contract and game
+    function signAction(
+        Vm.Wallet memory wallet,
+        uint256 gameId,
+        DeAction memory action
+    ) internal returns (bytes32[2] memory) {
+        bytes32 domainSeparator = keccak256(
+            abi.encode(
+                keccak256(
+                    "EIP712Domain(string name,string version,uint256 chainId,address verifyingContract,bytes32 salt)"
+                ),
+                keccak256("ZkBlackjack"),
+                keccak256("1"),
+                block.chainid,
+                address(zkBlackjack),
+                bytes32(gameId)
+            )
+        );
+        bytes32 structHash = keccak256(
+            abi.encode(
+                keccak256(
+                    "DeAction(uint8 nonce,uint8 handId,uint8 inner,uint8[] my_cards,uint8[] dealer_cards)"
+                ),
+                action.nonce,
+                action.handId,
+                action.inner,
+                keccak256(abi.encodePacked(action.myCards)),
+                keccak256(abi.encodePacked(action.dealerCards))
+            )
+        );
+        (, bytes32 r, bytes32 s) = vm.sign(
+            wallet.privateKey,
+            keccak256(abi.encodePacked("\x19\x01", domainSeparator, structHash))
+        );
+        return [r, s];
+    }
 }